@@ -0,0 +1,83 @@
+//! Exponential backoff with jitter, for retrying things that failed
+
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Keeps track of consecutive failed attempts and how long to wait before the next one
+#[derive(Debug)]
+pub struct Backoff {
+    /// The delay after the first failure
+    initial: Duration,
+    /// The longest delay between attempts
+    max: Duration,
+    /// The number of consecutive failed attempts
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            attempts: 0,
+        }
+    }
+
+    /// Returns the number of consecutive failed attempts
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Returns the delay before the next attempt, without jitter
+    fn base_delay(&self) -> Duration {
+        2u32.checked_pow(self.attempts)
+            .and_then(|factor| self.initial.checked_mul(factor))
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+
+    /// Records a failed attempt and returns how long to wait before the next one
+    ///
+    /// The delay is randomly picked between half and all of the exponential delay, so clients
+    /// that failed at the same time don't all retry at the same time.
+    pub fn fail(&mut self) -> Duration {
+        let delay = self.base_delay();
+        let half = delay / 2;
+        let jitter = rand::thread_rng().gen_range(0, half.as_millis() as u64 + 1);
+
+        self.attempts = self.attempts.saturating_add(1);
+
+        half + Duration::from_millis(jitter)
+    }
+
+    /// Records a successful attempt, which starts the delays over
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        let expected = [1, 2, 4, 8, 10, 10];
+
+        for (attempts, &secs) in expected.iter().enumerate() {
+            assert_eq!(backoff.attempts(), attempts as u32);
+            assert_eq!(backoff.base_delay(), Duration::from_secs(secs));
+
+            let delay = backoff.fail();
+            assert!(delay >= Duration::from_secs(secs) / 2);
+            assert!(delay <= Duration::from_secs(secs));
+        }
+
+        backoff.attempts = 100;
+        assert_eq!(backoff.base_delay(), Duration::from_secs(10));
+
+        backoff.reset();
+        assert_eq!(backoff.base_delay(), Duration::from_secs(1));
+    }
+}
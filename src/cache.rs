@@ -0,0 +1,350 @@
+//! A size-limited cache with expiring entries
+//!
+//! The cache is limited both in its number of entries and in their total size, which is measured
+//! as the length of their JSON serialization.
+//!
+//! Entries can optionally be kept in a [`Storage`] namespace, in which case they're loaded back
+//! the first time the cache is used after a restart.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::CacheConfig;
+use crate::storage::Storage;
+
+/// The storage key prefix of persisted entries
+const KEY_PREFIX: &str = "cache/";
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    value: serde_json::Value,
+    expires_at: DateTime<Utc>,
+    /// A counter value from the last time the entry was used, for evicting the least recently
+    /// used entry
+    #[serde(skip)]
+    last_used: u64,
+    /// The size of the entry in bytes
+    #[serde(skip)]
+    size: usize,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Whether persisted entries have been loaded
+    loaded: bool,
+    entries: HashMap<String, Entry>,
+    counter: u64,
+    /// The total size of all entries in bytes
+    bytes: usize,
+}
+
+/// A cache that can be cheaply cloned and shared
+#[derive(Clone)]
+pub struct Cache {
+    state: Arc<Mutex<CacheState>>,
+    ttl: Duration,
+    max_entries: usize,
+    max_bytes: usize,
+    storage: Option<Storage>,
+}
+
+/// Normalizes a search query so that trivially different queries share a cache entry
+pub fn normalize_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+impl Entry {
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+}
+
+/// Returns the size in bytes that an entry with `key` and `value` counts for
+fn entry_size(key: &str, value: &serde_json::Value) -> usize {
+    key.len() + value.to_string().len()
+}
+
+impl Cache {
+    /// Constructs a new cache, persisting entries to `storage` if the config says so
+    pub fn new(config: &CacheConfig, storage: Storage) -> Cache {
+        Cache {
+            state: Arc::new(Mutex::new(CacheState::default())),
+            ttl: Duration::seconds(config.ttl as i64),
+            max_entries: config.max_entries,
+            max_bytes: config.max_bytes,
+            storage: if config.persist { Some(storage) } else { None },
+        }
+    }
+
+    /// Returns the cached value of `key`, if it's present and hasn't expired
+    pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        if self.max_entries == 0 {
+            return None;
+        }
+
+        let now = Utc::now();
+        let mut state = self.state.lock().await;
+
+        self.load(&mut state, now).await;
+
+        state.counter += 1;
+
+        let counter = state.counter;
+        let entry = match state.entries.get_mut(key) {
+            Some(entry) if !entry.is_expired(now) => entry,
+            _ => return None,
+        };
+
+        entry.last_used = counter;
+
+        match serde_json::from_value(entry.value.clone()) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                warn!("Ignoring cache entry `{}' that can't be read: {}", key, err);
+
+                None
+            }
+        }
+    }
+
+    /// Inserts `value` into the cache, evicting the least recently used entry if it's full
+    pub async fn insert<T: Serialize>(&self, key: &str, value: &T) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        let value = match serde_json::to_value(value) {
+            Ok(value) => value,
+            Err(err) => {
+                warn!("Not caching `{}' that can't be serialized: {}", key, err);
+
+                return;
+            }
+        };
+
+        let size = entry_size(key, &value);
+
+        if size > self.max_bytes {
+            debug!(
+                "Not caching `{}', which is larger than the whole cache",
+                key
+            );
+
+            return;
+        }
+
+        let now = Utc::now();
+        let mut state = self.state.lock().await;
+
+        self.load(&mut state, now).await;
+
+        if let Some(old) = state.entries.remove(key) {
+            state.bytes -= old.size;
+        }
+
+        while state.entries.len() >= self.max_entries || state.bytes + size > self.max_bytes {
+            self.evict(&mut state, now).await;
+        }
+
+        state.counter += 1;
+        state.bytes += size;
+
+        let entry = Entry {
+            value,
+            expires_at: now + self.ttl,
+            last_used: state.counter,
+            size,
+        };
+
+        if let Some(ref storage) = self.storage {
+            if let Err(err) = storage.set(&format!("{}{}", KEY_PREFIX, key), &entry).await {
+                warn!("Failed to persist cache entry `{}': {}", key, err);
+            }
+        }
+
+        state.entries.insert(key.to_string(), entry);
+    }
+
+    /// Loads persisted entries the first time the cache is used, dropping expired ones
+    async fn load(&self, state: &mut CacheState, now: DateTime<Utc>) {
+        if state.loaded {
+            return;
+        }
+
+        state.loaded = true;
+
+        let storage = match self.storage {
+            Some(ref storage) => storage,
+            None => return,
+        };
+
+        let entries: Vec<(String, Entry)> = match storage.list(KEY_PREFIX).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("Failed to load persisted cache entries: {}", err);
+
+                return;
+            }
+        };
+
+        for (key, mut entry) in entries {
+            if entry.is_expired(now) {
+                let _ = storage.delete(&key).await;
+            } else {
+                let key = key[KEY_PREFIX.len()..].to_string();
+
+                entry.size = entry_size(&key, &entry.value);
+                state.bytes += entry.size;
+                state.entries.insert(key, entry);
+            }
+        }
+
+        debug!("Loaded {} cache entries", state.entries.len());
+
+        while state.entries.len() > self.max_entries || state.bytes > self.max_bytes {
+            self.evict(state, now).await;
+        }
+    }
+
+    /// Removes all expired entries, or the least recently used one if none have expired
+    async fn evict(&self, state: &mut CacheState, now: DateTime<Utc>) {
+        let mut evicted: Vec<String> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        if evicted.is_empty() {
+            evicted.extend(
+                state
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone()),
+            );
+        }
+
+        for key in evicted {
+            if let Some(entry) = state.entries.remove(&key) {
+                state.bytes -= entry.size;
+            }
+
+            if let Some(ref storage) = self.storage {
+                let _ = storage.delete(&format!("{}{}", KEY_PREFIX, key)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+
+    fn cache(ttl: u64, max_entries: usize, persist: bool, storage: Storage) -> Cache {
+        let config = CacheConfig {
+            ttl,
+            max_entries,
+            persist,
+            ..CacheConfig::default()
+        };
+
+        Cache::new(&config, storage)
+    }
+
+    fn storage() -> Storage {
+        Storage::new(Arc::new(MemoryBackend::new()), "test")
+    }
+
+    #[test]
+    fn test_normalize_query() {
+        assert_eq!(normalize_query("  Rust   ASYNC "), "rust async");
+    }
+
+    #[tokio::test]
+    async fn test_get_and_expire() {
+        let fresh = cache(60, 10, false, storage());
+        fresh.insert("rust", &"result".to_string()).await;
+        assert_eq!(
+            fresh.get::<String>("rust").await,
+            Some("result".to_string())
+        );
+        assert_eq!(fresh.get::<String>("go").await, None);
+
+        let expired = cache(0, 10, false, storage());
+        expired.insert("rust", &"result".to_string()).await;
+        assert_eq!(expired.get::<String>("rust").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used() {
+        let cache = cache(60, 2, false, storage());
+
+        cache.insert("a", &1).await;
+        cache.insert("b", &2).await;
+        assert_eq!(cache.get::<i32>("a").await, Some(1));
+
+        cache.insert("c", &3).await;
+        assert_eq!(cache.get::<i32>("a").await, Some(1));
+        assert_eq!(cache.get::<i32>("b").await, None);
+        assert_eq!(cache.get::<i32>("c").await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_evicts_over_max_bytes() {
+        let config = CacheConfig {
+            max_bytes: 100,
+            ..CacheConfig::default()
+        };
+        let cache = Cache::new(&config, storage());
+        let value = "x".repeat(40);
+
+        cache.insert("a", &value).await;
+        cache.insert("b", &value).await;
+        assert_eq!(cache.get::<String>("a").await, Some(value.clone()));
+
+        cache.insert("c", &value).await;
+        assert_eq!(cache.get::<String>("a").await, Some(value.clone()));
+        assert_eq!(cache.get::<String>("b").await, None);
+        assert_eq!(cache.get::<String>("c").await, Some(value));
+
+        cache.insert("d", &"x".repeat(200)).await;
+        assert_eq!(cache.get::<String>("d").await, None);
+        assert!(cache.get::<String>("a").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        let cache = cache(60, 0, false, storage());
+
+        cache.insert("a", &1).await;
+        assert_eq!(cache.get::<i32>("a").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_persist() {
+        let storage = storage();
+
+        let first = cache(60, 10, true, storage.clone());
+        first.insert("rust", &"result".to_string()).await;
+
+        let second = cache(60, 10, true, storage.clone());
+        assert_eq!(
+            second.get::<String>("rust").await,
+            Some("result".to_string())
+        );
+
+        let unpersisted = cache(60, 10, false, storage);
+        assert_eq!(unpersisted.get::<String>("rust").await, None);
+    }
+}
@@ -1,19 +1,55 @@
 //! This is a module that contains a high-level Matrix client
 
+use std::convert::TryFrom;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::{Mutex, RwLock};
+use tokio::time;
 
 use async_trait::async_trait;
+use chrono::Utc;
+use log::{debug, error, info, trace, warn};
 use matrix_sdk::{
-    events::{room::message::MessageEventContent, SyncMessageEvent},
-    Client, ClientConfig, EventEmitter, JsonStore, SyncRoom, SyncSettings,
+    api::r0::{message::get_message_events, sync::sync_events::Response as SyncResponse},
+    events::{
+        room::{
+            member::{MemberEventContent, MembershipState},
+            message::{MessageEventContent, TextMessageEventContent},
+            redaction::SyncRedactionEvent,
+            relationships::Relation,
+        },
+        StrippedStateEvent, SyncMessageEvent, SyncStateEvent,
+    },
+    Client, ClientConfig, CustomEvent, EventEmitter, JsonStore, SyncRoom, SyncSettings,
 };
+use matrix_sdk_common::identifiers::{EventId, RoomId, UserId};
 use url::Url;
 
-use crate::plugin::PluginRegistry;
+use crate::backoff::Backoff;
+use crate::cache::Cache;
+use crate::config::{CatchUpPolicy, ReceiptPolicy, ReceiptsConfig};
+use crate::context::{self, strip_reply_fallback, MessageContext, ReplyMap};
+use crate::dispatch::{Completion, Dispatcher, Event};
+use crate::error::SyncError;
+use crate::health;
+use crate::history::{History, HistoryMessage};
+use crate::http::HttpClient;
+use crate::membership::{self, InviteResponse, Member};
+use crate::metrics;
+use crate::plugin::{Plugin, PluginRegistry};
 use crate::plugins;
+use crate::reaction::{ButtonMap, Press, PressMap, Reaction};
+use crate::storage::{Storage, StorageBackend};
+use crate::sync_state::SyncState;
 use crate::{Config, Error};
 
+/// How long the homeserver may wait for new events before answering a sync
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+/// The largest number of pages of missed messages to fetch for each room after a sync
+const MAX_CATCH_UP_PAGES: usize = 10;
+/// How often to forget old replies, buttons and dispatched events
+const PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
 #[derive(Clone)]
 pub struct MatrixClient {
     /// The inner, slightly lower level Matrix client
@@ -22,6 +58,22 @@ pub struct MatrixClient {
     config: Arc<Mutex<Config>>,
     /// The plugin registry
     plugin_registry: Arc<RwLock<PluginRegistry>>,
+    /// Our replies to messages, so edited and redacted messages can update their replies
+    replies: Arc<ReplyMap>,
+    /// The messages with reaction buttons, and the plugins they belong to
+    buttons: Arc<ButtonMap>,
+    /// The reactions that clicked buttons, so their plugins can be told when they're removed
+    presses: Arc<PressMap>,
+    /// The recent messages in each room
+    history: Arc<History>,
+    /// Runs the hooks of plugins
+    dispatcher: Arc<Dispatcher>,
+    /// Which messages to send read receipts for, and whether to react to commands
+    receipts: ReceiptsConfig,
+    /// The saved sync token and the events that have been dispatched
+    sync_state: Arc<SyncState>,
+    /// Events sent before this are too old to catch up on after a restart
+    catch_up_cutoff: Option<SystemTime>,
 }
 
 struct PluginEventDispatcher {
@@ -34,106 +86,325 @@ impl PluginEventDispatcher {
     }
 }
 
+/// Returns the content to dispatch for a message, and the id of the message it replaces if it's
+/// an edit
+fn edited_content(content: &MessageEventContent) -> (&MessageEventContent, Option<EventId>) {
+    if let MessageEventContent::Text(TextMessageEventContent {
+        relates_to: Some(Relation::Replacement(replacement)),
+        new_content: Some(new_content),
+        ..
+    }) = content
+    {
+        return (new_content, Some(replacement.event_id.clone()));
+    }
+
+    (content, None)
+}
+
+/// Returns the id of the message that a message replies to, if any
+fn in_reply_to(content: &MessageEventContent) -> Option<EventId> {
+    match content {
+        MessageEventContent::Text(TextMessageEventContent {
+            relates_to: Some(Relation::Reply { in_reply_to }),
+            ..
+        }) => Some(in_reply_to.event_id.clone()),
+        _ => None,
+    }
+}
+
 #[async_trait]
 impl EventEmitter for PluginEventDispatcher {
     async fn on_room_message(&self, room: SyncRoom, event: &SyncMessageEvent<MessageEventContent>) {
         if let SyncRoom::Joined(room) = room {
-            let user_id = &event.sender;
             let room_id = room.read().await.room_id.clone();
 
-            // TODO: Figure out how to use dynamic dispatch on the Plugin trait instead of this
-            // nonsense
-            match &event.content {
-                MessageEventContent::Audio(content) => {
-                    for plugin in self.client.plugin_registry.read().await.plugins().iter() {
-                        plugin
-                            .on_room_audio_message(user_id, &room_id, content)
-                            .await;
-                    }
-                }
-                MessageEventContent::Emote(content) => {
-                    for plugin in self.client.plugin_registry.read().await.plugins().iter() {
-                        plugin
-                            .on_room_emote_message(user_id, &room_id, content)
-                            .await;
+            self.client.record_history(&room_id, event);
+            self.client.handle_room_message(room_id, event).await;
+        }
+    }
+
+    async fn on_room_member(&self, room: SyncRoom, event: &SyncStateEvent<MemberEventContent>) {
+        if let SyncRoom::Joined(room) = room {
+            let change =
+                match membership::membership_change(&event.content, event.prev_content.as_ref()) {
+                    Some(change) => change,
+                    None => return,
+                };
+            let user_id = match UserId::try_from(event.state_key.as_str()) {
+                Ok(user_id) => user_id,
+                Err(_) => return,
+            };
+            let client = self.client.inner.read().await.clone();
+
+            // Don't greet ourselves
+            if client.user_id().await.as_ref() == Some(&user_id) {
+                return;
+            }
+
+            if !self
+                .client
+                .should_dispatch(&event.event_id, event.origin_server_ts)
+                .await
+            {
+                return;
+            }
+
+            let room_id = room.read().await.room_id.clone();
+            let ctx = MessageContext::new(
+                client,
+                self.client.replies.clone(),
+                self.client.buttons.clone(),
+                room_id,
+                event.sender.clone(),
+                event.event_id.clone(),
+                None,
+            );
+            let member = Member {
+                user_id,
+                display_name: event.content.displayname.clone(),
+                change,
+            };
+
+            self.client.dispatch(Event::Member(ctx, member)).await;
+            self.client.mark_dispatched(&event.event_id).await;
+        }
+    }
+
+    async fn on_stripped_state_member(
+        &self,
+        room: SyncRoom,
+        event: &StrippedStateEvent<MemberEventContent>,
+        _: Option<MemberEventContent>,
+    ) {
+        if let SyncRoom::Invited(room) = room {
+            if event.content.membership != MembershipState::Invite {
+                return;
+            }
+
+            let client = self.client.inner.read().await.clone();
+
+            // Only invites of ourselves are of interest
+            let invited_us = client
+                .user_id()
+                .await
+                .map_or(false, |user_id| user_id.as_str() == event.state_key);
+
+            if !invited_us {
+                return;
+            }
+
+            let room_id = room.read().await.room_id.clone();
+            let response = {
+                let config = self.client.config.lock().await;
+
+                membership::respond_to_invite(&config.matrix.invites, &event.sender)
+            };
+
+            match response {
+                InviteResponse::Accept => {
+                    debug!("Accepting invite to {} from {}", room_id, event.sender);
+
+                    if let Err(err) = client.join_room_by_id(&room_id).await {
+                        warn!("Failed to join {}: {}", room_id, err);
                     }
                 }
-                MessageEventContent::File(content) => {
-                    for plugin in self.client.plugin_registry.read().await.plugins().iter() {
-                        plugin
-                            .on_room_file_message(user_id, &room_id, content)
-                            .await;
+                InviteResponse::Reject => {
+                    debug!("Rejecting invite to {} from {}", room_id, event.sender);
+
+                    if let Err(err) = client.leave_room(&room_id).await {
+                        warn!("Failed to reject invite to {}: {}", room_id, err);
                     }
                 }
-                MessageEventContent::Image(content) => {
-                    for plugin in self.client.plugin_registry.read().await.plugins().iter() {
-                        plugin
-                            .on_room_image_message(user_id, &room_id, content)
-                            .await;
-                    }
+                InviteResponse::Ignore => {
+                    debug!("Ignoring invite to {} from {}", room_id, event.sender);
                 }
-                MessageEventContent::Location(content) => {
-                    for plugin in self.client.plugin_registry.read().await.plugins().iter() {
-                        plugin
-                            .on_room_location_message(user_id, &room_id, content)
-                            .await;
-                    }
+            }
+        }
+    }
+
+    async fn on_custom_event(&self, room: SyncRoom, event: &CustomEvent<'_>) {
+        let event = match event {
+            CustomEvent::Message(event) => event,
+            _ => return,
+        };
+
+        if let SyncRoom::Joined(room) = room {
+            let client = self.client.inner.read().await.clone();
+
+            // Don't react to our own events, which includes the buttons we add
+            if client.user_id().await.as_ref() == Some(&event.sender) {
+                return;
+            }
+
+            if !self
+                .client
+                .should_dispatch(&event.event_id, event.origin_server_ts)
+                .await
+            {
+                return;
+            }
+
+            let room_id = room.read().await.room_id.clone();
+            let ctx = MessageContext::new(
+                client,
+                self.client.replies.clone(),
+                self.client.buttons.clone(),
+                room_id,
+                event.sender.clone(),
+                event.event_id.clone(),
+                None,
+            );
+
+            self.client
+                .dispatch(Event::Custom(ctx.clone(), event.content.clone()))
+                .await;
+            self.client.mark_dispatched(&event.event_id).await;
+
+            let reaction = match Reaction::from_custom_content(&event.content) {
+                Some(reaction) => reaction,
+                None => return,
+            };
+            let buttons = match self.client.buttons.get(&reaction.event_id).await {
+                Ok(buttons) => buttons.filter(|buttons| buttons.keys.contains(&reaction.key)),
+                Err(err) => {
+                    warn!(
+                        "Failed to look up the buttons of {}: {}",
+                        reaction.event_id, err
+                    );
+
+                    None
                 }
-                MessageEventContent::Notice(content) => {
-                    for plugin in self.client.plugin_registry.read().await.plugins().iter() {
-                        plugin
-                            .on_room_notice_message(user_id, &room_id, content)
-                            .await;
-                    }
+            };
+
+            self.client
+                .dispatch(Event::Reaction(ctx.clone(), reaction.clone()))
+                .await;
+
+            if let Some(buttons) = buttons {
+                let press = Press {
+                    room_id: ctx.room_id.clone(),
+                    sender: event.sender.clone(),
+                    event_id: reaction.event_id.clone(),
+                    key: reaction.key.clone(),
+                    plugin: buttons.plugin,
+                    pressed_at: Utc::now(),
+                };
+
+                if let Err(err) = self.client.presses.insert(&event.event_id, &press).await {
+                    warn!(
+                        "Failed to remember the button press {}: {}",
+                        event.event_id, err
+                    );
                 }
-                MessageEventContent::ServerNotice(content) => {
-                    for plugin in self.client.plugin_registry.read().await.plugins().iter() {
-                        plugin
-                            .on_room_server_notice_message(user_id, &room_id, content)
-                            .await;
-                    }
+
+                self.client
+                    .dispatch_to(Event::Button(ctx, reaction), &press.plugin)
+                    .await;
+            }
+        }
+    }
+
+    async fn on_room_redaction(&self, room: SyncRoom, event: &SyncRedactionEvent) {
+        if let SyncRoom::Joined(room) = room {
+            let room_id = room.read().await.room_id.clone();
+
+            self.client.history.redact(&room_id, &event.redacts);
+
+            match self.client.presses.remove(&event.redacts).await {
+                Ok(Some(press)) if press.room_id == room_id => {
+                    self.client.release_button(&event.redacts, press).await
                 }
-                MessageEventContent::Text(content) => {
-                    println!("Received text message: {:?}", content);
+                Ok(_) => {}
+                Err(err) => warn!(
+                    "Failed to look up the button press {}: {}",
+                    event.redacts, err
+                ),
+            }
 
-                    for plugin in self.client.plugin_registry.read().await.plugins().iter() {
-                        plugin
-                            .on_room_text_message(user_id, &room_id, content)
-                            .await;
-                    }
+            let replies = match self.client.replies.remove(&event.redacts).await {
+                Ok(Some(replies)) if replies.room_id == room_id => replies,
+                Ok(_) => return,
+                Err(err) => {
+                    warn!(
+                        "Failed to look up our replies to {}: {}",
+                        event.redacts, err
+                    );
+
+                    return;
                 }
-                MessageEventContent::Video(content) => {
-                    println!("Received video message: {:?}", content);
+            };
 
-                    for plugin in self.client.plugin_registry.read().await.plugins().iter() {
-                        plugin
-                            .on_room_video_message(user_id, &room_id, content)
-                            .await;
-                    }
+            let client = self.client.inner.read().await.clone();
+
+            for reply in replies.event_ids {
+                // Use a transaction id derived from the reply, so retries aren't redacted twice
+                let txn_id = format!("redact-{}", reply);
+                let reason = "The message this replied to was deleted";
+
+                match context::redact(&client, &room_id, &reply, &txn_id, reason).await {
+                    Ok(_) => debug!("Redacted our reply {} to {}", reply, event.redacts),
+                    Err(err) => warn!("Failed to redact our reply {}: {}", reply, err),
                 }
-                _ => {}
             }
         }
     }
 }
 
 impl MatrixClient {
-    /// Creates a new MatrixClient with a given parsed `config`
-    pub fn with_config(config: Config) -> Result<MatrixClient, Error> {
-        let store = JsonStore::open("matrix_state").unwrap();
+    /// Creates a new MatrixClient with a given parsed `config`, handing out plugin storage from
+    /// `storage_backend`
+    pub fn with_config(
+        config: Config,
+        storage_backend: Arc<dyn StorageBackend>,
+    ) -> Result<MatrixClient, Error> {
+        // The state store and the crypto store both live here. Losing the crypto store means
+        // that the bot can no longer decrypt messages in encrypted rooms
+        let store_path = config.data_dir.join("matrix");
+        let store = JsonStore::open(&store_path)?;
         let client_config = ClientConfig::new()
             .state_store(Box::new(store))
-            .store_path("/home/mk/Projects/meta-matrix/test");
+            .store_path(&store_path);
 
         let homeserver_url =
             Url::parse(&config.matrix.homeserver).map_err(Error::HomeserverParseError)?;
 
         let client = Client::new_with_config(homeserver_url, client_config)?;
+        let http_cache = Cache::new(&config.cache, Storage::new(storage_backend.clone(), "http"));
+        let http = HttpClient::new(config.http.clone(), http_cache)?;
+        let replies = ReplyMap::new(Storage::new(storage_backend.clone(), "replies"));
+        let buttons = ButtonMap::new(Storage::new(storage_backend.clone(), "buttons"));
+        let presses = PressMap::new(Storage::new(storage_backend.clone(), "presses"));
+        let sync_state = SyncState::new(Storage::new(storage_backend.clone(), "sync"));
+        let history = Arc::new(History::new(client.clone(), config.history));
+        let catch_up_cutoff = match config.sync.catch_up {
+            CatchUpPolicy::Recent => {
+                Some(SystemTime::now() - Duration::from_secs(config.sync.catch_up_max_age * 60))
+            }
+            CatchUpPolicy::Skip | CatchUpPolicy::All => None,
+        };
+        let plugin_registry = PluginRegistry::new(
+            client.clone(),
+            Arc::new(config.clone()),
+            storage_backend,
+            http,
+            history.clone(),
+        );
+
+        let dispatcher = Dispatcher::new(config.dispatch.clone());
+        let receipts = config.receipts;
 
         Ok(MatrixClient {
-            inner: Arc::new(RwLock::new(client.clone())),
+            inner: Arc::new(RwLock::new(client)),
             config: Arc::new(Mutex::new(config)),
-            plugin_registry: Arc::new(RwLock::new(PluginRegistry::new(client))),
+            plugin_registry: Arc::new(RwLock::new(plugin_registry)),
+            replies: Arc::new(replies),
+            buttons: Arc::new(buttons),
+            presses: Arc::new(presses),
+            history,
+            dispatcher: Arc::new(dispatcher),
+            receipts,
+            sync_state: Arc::new(sync_state),
+            catch_up_cutoff,
         })
     }
 
@@ -151,8 +422,39 @@ impl MatrixClient {
             )
             .await?;
 
-        // Sync to skip old messages
-        client.sync(SyncSettings::default()).await?;
+        health::set_logged_in();
+
+        let token = match self.sync_state.token().await {
+            Ok(token) => token,
+            Err(err) => {
+                warn!("Failed to load the sync token: {}", err);
+                None
+            }
+        };
+
+        match (config.sync.catch_up, token) {
+            (CatchUpPolicy::Skip, _) | (_, None) => {
+                // Without a token, we can't tell which messages we've already answered
+                info!("Skipping messages sent since the last sync");
+
+                let response = client.sync(SyncSettings::default()).await.map_err(|err| {
+                    metrics::SYNC_ERRORS.inc();
+                    err
+                })?;
+
+                self.save_sync_token(&response).await;
+                health::record_sync();
+            }
+            (_, Some(_)) => info!("Catching up on messages sent since the last sync"),
+        }
+
+        let pruner = self.clone();
+        tokio::spawn(async move {
+            loop {
+                pruner.prune().await;
+                time::delay_for(PRUNE_INTERVAL).await;
+            }
+        });
 
         client
             .add_event_emitter(Box::new(PluginEventDispatcher::new(self.clone())))
@@ -161,15 +463,404 @@ impl MatrixClient {
         Ok(())
     }
 
-    /// Continually `sync`s with the homeserver for new updates until an error occurs
+    /// Adds a text message to the recent history of its room, or updates the message it edits
+    fn record_history(&self, room_id: &RoomId, event: &SyncMessageEvent<MessageEventContent>) {
+        let (content, replaces) = edited_content(&event.content);
+        let body = match content {
+            MessageEventContent::Text(text) => strip_reply_fallback(&text.body),
+            _ => return,
+        };
+
+        match replaces {
+            Some(original) => self.history.edit(room_id, &original, &event.sender, body),
+            None => self.history.record(
+                room_id,
+                HistoryMessage {
+                    event_id: event.event_id.clone(),
+                    sender: event.sender.clone(),
+                    body: body.to_string(),
+                    sent_at: event.origin_server_ts.into(),
+                },
+            ),
+        }
+    }
+
+    /// Dispatches a message to plugins, unless we sent it ourselves or it shouldn't be dispatched
+    async fn handle_room_message(
+        &self,
+        room_id: RoomId,
+        event: &SyncMessageEvent<MessageEventContent>,
+    ) {
+        let client = self.inner.read().await.clone();
+
+        // Don't react to our own messages, which includes our edits of earlier replies
+        if client.user_id().await.as_ref() == Some(&event.sender) {
+            return;
+        }
+
+        if !self
+            .should_dispatch(&event.event_id, event.origin_server_ts)
+            .await
+        {
+            return;
+        }
+
+        let (content, replaces) = edited_content(&event.content);
+        let ctx = MessageContext::new(
+            client,
+            self.replies.clone(),
+            self.buttons.clone(),
+            room_id,
+            event.sender.clone(),
+            event.event_id.clone(),
+            replaces,
+        )
+        .with_in_reply_to(in_reply_to(&event.content))
+        .with_error_reactions(self.receipts.reactions);
+        let completion = self
+            .dispatch(Event::Message(ctx.clone(), content.clone()))
+            .await;
+
+        self.mark_dispatched(&event.event_id).await;
+        self.acknowledge(ctx, completion);
+    }
+
+    /// Dispatches the messages that a limited timeline of `room_id` left out, by paginating
+    /// backwards from `prev_batch` until we reach messages that were already dispatched or that
+    /// are too old to catch up on
+    ///
+    /// These messages are dispatched after the newer ones that the sync did include. Only
+    /// unencrypted messages can be caught up on this way, and other events such as membership
+    /// changes and reactions in the gap are skipped.
+    async fn catch_up(
+        &self,
+        client: &Client,
+        room_id: &RoomId,
+        prev_batch: &str,
+    ) -> Result<(), matrix_sdk::Error> {
+        let mut from = prev_batch.to_string();
+        let mut events = vec![];
+
+        'pages: for _ in 0..MAX_CATCH_UP_PAGES {
+            let request = get_message_events::Request::backward(room_id, &from);
+            let response = client.send(request).await?;
+
+            for event in response.chunk.iter() {
+                let event = serde_json::to_value(event)?;
+
+                if event["type"] != "m.room.message" {
+                    continue;
+                }
+
+                let event: SyncMessageEvent<MessageEventContent> =
+                    match serde_json::from_value(event) {
+                        Ok(event) => event,
+                        Err(_) => continue,
+                    };
+
+                if !self
+                    .should_dispatch(&event.event_id, event.origin_server_ts)
+                    .await
+                {
+                    break 'pages;
+                }
+
+                events.push(event);
+            }
+
+            from = match response.end {
+                Some(end) if !response.chunk.is_empty() => end,
+                _ => break,
+            };
+        }
+
+        debug!(
+            "Catching up on {} messages that the sync left out of {}",
+            events.len(),
+            room_id
+        );
+
+        // The homeserver returns the newest messages first
+        for event in events.iter().rev() {
+            self.handle_room_message(room_id.clone(), event).await;
+        }
+
+        Ok(())
+    }
+
+    /// Forgets old replies, buttons and dispatched events, so storage doesn't keep growing
+    async fn prune(&self) {
+        match self.replies.prune().await {
+            Ok(pruned) => debug!("Forgot {} old replies", pruned),
+            Err(err) => warn!("Failed to prune old replies: {}", err),
+        }
+
+        match self.buttons.prune().await {
+            Ok(pruned) => debug!("Forgot the buttons of {} old messages", pruned),
+            Err(err) => warn!("Failed to prune old buttons: {}", err),
+        }
+
+        match self.presses.prune().await {
+            Ok(pruned) => debug!("Forgot {} old button presses", pruned),
+            Err(err) => warn!("Failed to prune old button presses: {}", err),
+        }
+
+        match self.sync_state.prune().await {
+            Ok(pruned) => debug!("Forgot {} old dispatched events", pruned),
+            Err(err) => warn!("Failed to prune old dispatched events: {}", err),
+        }
+    }
+
+    /// Returns whether an event should be dispatched to plugins, which it shouldn't if it's older
+    /// than the catch-up policy allows, or if it has been dispatched before
+    ///
+    /// Events are only remembered with [`MatrixClient::mark_dispatched`] once their jobs are
+    /// queued, so an event that we stopped before dispatching is dispatched again after a restart.
+    async fn should_dispatch(&self, event_id: &EventId, origin_server_ts: SystemTime) -> bool {
+        if let Some(cutoff) = self.catch_up_cutoff {
+            if origin_server_ts < cutoff {
+                debug!("Skipping {}, which is too old to catch up on", event_id);
+
+                return false;
+            }
+        }
+
+        match self.sync_state.is_handled(event_id).await {
+            Ok(false) => true,
+            Ok(true) => {
+                debug!("Skipping {}, which was already dispatched", event_id);
+
+                false
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to check whether {} was dispatched: {}",
+                    event_id, err
+                );
+
+                true
+            }
+        }
+    }
+
+    /// Remembers that the jobs for an event were queued, so it isn't dispatched again
+    async fn mark_dispatched(&self, event_id: &EventId) {
+        if let Err(err) = self.sync_state.mark_handled(event_id).await {
+            warn!(
+                "Failed to remember that {} was dispatched: {}",
+                event_id, err
+            );
+        }
+    }
+
+    /// Saves the token of a sync response, so we can continue from it after restarting
+    async fn save_sync_token(&self, response: &SyncResponse) {
+        if let Err(err) = self.sync_state.set_token(&response.next_batch).await {
+            warn!("Failed to save the sync token: {}", err);
+        }
+    }
+
+    /// Returns the token to continue syncing from, if any
+    async fn sync_token(&self, client: &Client) -> Option<String> {
+        match client.sync_token().await {
+            Some(token) => Some(token),
+            None => self.sync_state.token().await.unwrap_or(None),
+        }
+    }
+
+    /// Handles a successful sync, after its room events have been dispatched to plugins
+    ///
+    /// To-device events aren't passed to event emitters, so they're dispatched here instead. If we
+    /// catch up on missed messages, the ones that limited timelines left out are fetched and
+    /// dispatched here too.
+    async fn handle_sync_response(&self, client: &Client, response: &SyncResponse) {
+        health::record_sync();
+        self.save_sync_token(response).await;
+
+        let events = response
+            .to_device
+            .events
+            .iter()
+            .filter_map(|event| event.deserialize().ok());
+
+        for event in events {
+            self.dispatch(Event::ToDevice(event)).await;
+        }
+
+        if self.config.lock().await.sync.catch_up == CatchUpPolicy::Skip {
+            return;
+        }
+
+        for (room_id, room) in response.rooms.join.iter() {
+            let prev_batch = match &room.timeline.prev_batch {
+                Some(prev_batch) if room.timeline.limited => prev_batch,
+                _ => continue,
+            };
+
+            if let Err(err) = self.catch_up(client, room_id, prev_batch).await {
+                warn!("Failed to catch up on messages in {}: {}", room_id, err);
+            }
+        }
+    }
+
+    /// Returns all the registered plugins
+    async fn plugins(&self) -> Vec<Arc<dyn Plugin>> {
+        self.plugin_registry.read().await.plugins().to_vec()
+    }
+
+    /// Queues `event` to be handled by all the plugins
+    async fn dispatch(&self, event: Event) -> Completion {
+        let plugins = self.plugins().await;
+
+        self.dispatcher.dispatch(event, plugins).await
+    }
+
+    /// Queues `event` to be handled by the plugin named `name` only
+    async fn dispatch_to(&self, event: Event, name: &str) -> Completion {
+        let plugins = self
+            .plugins()
+            .await
+            .into_iter()
+            .filter(|plugin| plugin.name() == name)
+            .collect();
+
+        self.dispatcher.dispatch(event, plugins).await
+    }
+
+    /// Tells the plugin that a button belongs to that the reaction `event_id` which clicked it
+    /// was removed
+    async fn release_button(&self, event_id: &EventId, press: Press) {
+        let client = self.inner.read().await.clone();
+        let reaction = press.reaction();
+        let ctx = MessageContext::new(
+            client,
+            self.replies.clone(),
+            self.buttons.clone(),
+            press.room_id,
+            press.sender,
+            event_id.clone(),
+            None,
+        );
+
+        self.dispatch_to(Event::ButtonReleased(ctx, reaction), &press.plugin)
+            .await;
+    }
+
+    /// Sends a read receipt for the message of `ctx`, and reacts to it if it was a command, as
+    /// configured, once the plugins are done with it
+    fn acknowledge(&self, ctx: MessageContext, completion: Completion) {
+        let config = self.receipts;
+
+        if config.policy == ReceiptPolicy::None && !config.reactions {
+            return;
+        }
+
+        tokio::spawn(async move {
+            if config.policy == ReceiptPolicy::All {
+                if let Err(err) = ctx.send_read_receipt().await {
+                    debug!("Failed to send read receipt for {}: {}", ctx.event_id, err);
+                }
+            }
+
+            if config.policy != ReceiptPolicy::Handled && !config.reactions {
+                return;
+            }
+
+            completion.wait().await;
+
+            if config.reactions {
+                let key = match ctx.command_result() {
+                    Some(true) => Some("✅"),
+                    Some(false) => Some("❌"),
+                    None => None,
+                };
+
+                if let Some(key) = key {
+                    if let Err(err) = ctx.react(key).await {
+                        warn!("Failed to react to {}: {}", ctx.event_id, err);
+                    }
+                }
+            }
+
+            if config.policy == ReceiptPolicy::Handled && ctx.was_handled() {
+                if let Err(err) = ctx.send_read_receipt().await {
+                    debug!("Failed to send read receipt for {}: {}", ctx.event_id, err);
+                }
+            }
+        });
+    }
+
+    /// Syncs with the homeserver once, failing if the request takes longer than `stall_timeout`
+    async fn sync_once(&self, client: &Client, stall_timeout: Duration) -> Result<(), SyncError> {
+        let mut settings = SyncSettings::default().timeout(SYNC_TIMEOUT);
+
+        if let Some(token) = self.sync_token(client).await {
+            settings = settings.token(token);
+        }
+
+        let started = Instant::now();
+        let response = time::timeout(stall_timeout, client.sync(settings))
+            .await
+            .map_err(|_| SyncError::Stalled(stall_timeout.as_secs()))??;
+
+        metrics::SYNC_DURATION.observe(started.elapsed().as_secs_f64());
+
+        self.handle_sync_response(client, &response).await;
+
+        Ok(())
+    }
+
+    /// Continually `sync`s with the homeserver for new updates
+    ///
+    /// Uploading and claiming encryption keys and decrypting events happens as part of syncing,
+    /// so incoming events are already decrypted by the time they're dispatched to plugins.
+    ///
+    /// Every failed sync is retried with exponential backoff, and we only return an error once
+    /// the configured number of attempts in a row have failed.
     pub async fn poll(&self) -> Result<(), Error> {
         let client = self.inner.read().await;
+        let config = self.config.lock().await.sync.clone();
+        let stall_timeout = Duration::from_secs(config.stall_timeout);
+        let mut backoff = Backoff::new(
+            Duration::from_secs(config.initial_backoff),
+            Duration::from_secs(config.max_backoff),
+        );
 
-        // Sync forever with our stored token
-        let settings = SyncSettings::default().token(client.sync_token().await.unwrap());
-        client.sync_forever(settings, |_| async {}).await;
+        info!("Syncing with the homeserver");
 
-        Ok(())
+        loop {
+            let err = match self.sync_once(&client, stall_timeout).await {
+                Ok(()) => {
+                    if backoff.attempts() > 0 {
+                        info!("Reconnected to the homeserver");
+                        metrics::SYNC_RECONNECTS.inc();
+                        backoff.reset();
+                    }
+
+                    continue;
+                }
+                Err(err) => err,
+            };
+
+            error!("Failed to sync with the homeserver: {}", err);
+            metrics::SYNC_ERRORS.inc();
+
+            if config.max_retries != 0 && backoff.attempts() >= config.max_retries {
+                return Err(Error::SyncFailed {
+                    attempts: backoff.attempts(),
+                    last_error: err,
+                });
+            }
+
+            let delay = backoff.fail();
+
+            info!(
+                "Retrying in {:.1} seconds (attempt {})",
+                delay.as_secs_f64(),
+                backoff.attempts()
+            );
+            time::delay_for(delay).await;
+        }
     }
 
     /// Initializes the plugin registry
@@ -178,7 +869,23 @@ impl MatrixClient {
 
         registry.register::<plugins::google_search::GoogleSearchPlugin>()?;
         registry.register::<plugins::choices::ChoicesPlugin>()?;
+        registry.register::<plugins::reminders::RemindersPlugin>()?;
+        registry.register::<plugins::url_preview::UrlPreviewPlugin>()?;
+        registry.register::<plugins::poll::PollPlugin>()?;
+        registry.register::<plugins::greeter::GreeterPlugin>()?;
+        registry.register::<plugins::encryption::EncryptionPlugin>()?;
+        registry.register::<plugins::admin::AdminPlugin>()?;
+        registry.register::<plugins::sed::SedPlugin>()?;
+        registry.register::<plugins::quotes::QuotesPlugin>()?;
+        registry.register::<plugins::karma::KarmaPlugin>()?;
 
         Ok(())
     }
+
+    /// Starts the registered plugins
+    ///
+    /// This should be called after logging in, so that plugins are able to send messages
+    pub async fn start_plugins(&self) -> Result<(), Error> {
+        self.plugin_registry.read().await.start().await
+    }
 }
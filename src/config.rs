@@ -1,7 +1,9 @@
 //! This is the module for the user configurations
 
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 
 use serde::Deserialize;
 
@@ -12,6 +14,304 @@ use crate::Error;
 pub struct Config {
     /// The matrix-specific configuration
     pub matrix: MatrixConfig,
+    /// The directory in which persistent data is kept
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf,
+    /// The configuration for outbound HTTP requests made by plugins
+    #[serde(default)]
+    pub http: HttpConfig,
+    /// The configuration for caching of HTTP responses and search results
+    #[serde(default)]
+    pub cache: CacheConfig,
+    /// The configuration for syncing with the homeserver
+    #[serde(default)]
+    pub sync: SyncConfig,
+    /// The configuration for dispatching events to plugins
+    #[serde(default)]
+    pub dispatch: DispatchConfig,
+    /// The configuration for read receipts and reactions to commands
+    #[serde(default)]
+    pub receipts: ReceiptsConfig,
+    /// The configuration for the recent messages that are kept for plugins
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// The configuration for end-to-end encryption
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+    /// The configuration for logging
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// The configuration for the metrics endpoint
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    /// The configuration for the health check endpoints
+    #[serde(default)]
+    pub health: HealthConfig,
+    /// Plugin-specific configuration
+    #[serde(default)]
+    pub plugins: PluginsConfig,
+}
+
+/// The configuration for outbound HTTP requests made by plugins
+#[derive(Clone, Debug, Deserialize)]
+pub struct HttpConfig {
+    /// The user agent to send with every request
+    #[serde(default = "default_http_user_agent")]
+    pub user_agent: String,
+    /// The maximum number of seconds a request may take, including following redirects and
+    /// reading the body
+    #[serde(default = "default_http_timeout")]
+    pub timeout: u64,
+    /// The maximum number of seconds to wait for a connection to be established
+    #[serde(default = "default_http_connect_timeout")]
+    pub connect_timeout: u64,
+    /// The maximum number of bytes to read from a response body
+    #[serde(default = "default_http_max_body_size")]
+    pub max_body_size: usize,
+    /// The maximum number of redirects to follow
+    #[serde(default = "default_http_max_redirects")]
+    pub max_redirects: usize,
+    /// Allow requests to loopback, link-local and private addresses
+    ///
+    /// NOTE: this lets anyone who can talk to the bot make it fetch internal resources
+    #[serde(default)]
+    pub allow_private_addresses: bool,
+}
+
+/// The format of log lines
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    Pretty,
+    /// A JSON object per line
+    Json,
+}
+
+/// The configuration for logging
+#[derive(Clone, Debug, Deserialize)]
+pub struct LoggingConfig {
+    /// The format of log lines
+    #[serde(default = "default_log_format")]
+    pub format: LogFormat,
+    /// The default log level, e.g. `info` or `debug`
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    /// Log levels of individual plugins, by the type name in their log lines, e.g.
+    /// `UrlPreviewPlugin = "debug"`
+    #[serde(default)]
+    pub plugins: HashMap<String, String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            format: default_log_format(),
+            level: default_log_level(),
+            plugins: HashMap::new(),
+        }
+    }
+}
+
+/// Which messages that were sent while we weren't running to handle when starting
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CatchUpPolicy {
+    /// Don't handle any of them
+    Skip,
+    /// Handle the ones that are at most `catch_up_max_age` minutes old
+    Recent,
+    /// Handle all of them
+    All,
+}
+
+/// The configuration for syncing with the homeserver
+#[derive(Clone, Debug, Deserialize)]
+pub struct SyncConfig {
+    /// The number of seconds a sync may take before it counts as failed, which has to be longer
+    /// than the 30 seconds that the homeserver may wait for new events
+    #[serde(default = "default_sync_stall_timeout")]
+    pub stall_timeout: u64,
+    /// The number of seconds to wait before the first attempt to reconnect
+    #[serde(default = "default_sync_initial_backoff")]
+    pub initial_backoff: u64,
+    /// The maximum number of seconds to wait between attempts to reconnect
+    #[serde(default = "default_sync_max_backoff")]
+    pub max_backoff: u64,
+    /// Which messages that were sent while we weren't running to handle when starting
+    ///
+    /// Unless this is `skip`, messages that a sync leaves out of a room because there were too
+    /// many are fetched from the homeserver and handled as well.
+    #[serde(default = "default_sync_catch_up")]
+    pub catch_up: CatchUpPolicy,
+    /// The number of minutes old messages may be for the `recent` catch-up policy
+    #[serde(default = "default_sync_catch_up_max_age")]
+    pub catch_up_max_age: u64,
+    /// The number of failed attempts to reconnect in a row after which we give up and exit
+    ///
+    /// Setting this to 0 makes us try forever
+    #[serde(default = "default_sync_max_retries")]
+    pub max_retries: u32,
+}
+
+impl Default for SyncConfig {
+    fn default() -> SyncConfig {
+        SyncConfig {
+            stall_timeout: default_sync_stall_timeout(),
+            initial_backoff: default_sync_initial_backoff(),
+            max_backoff: default_sync_max_backoff(),
+            catch_up: default_sync_catch_up(),
+            catch_up_max_age: default_sync_catch_up_max_age(),
+            max_retries: default_sync_max_retries(),
+        }
+    }
+}
+
+/// The configuration for dispatching events to plugins
+#[derive(Clone, Debug, Deserialize)]
+pub struct DispatchConfig {
+    /// The number of events that plugins can handle at the same time, besides the ones that
+    /// ordered plugins handle one room at a time
+    #[serde(default = "default_dispatch_workers")]
+    pub workers: usize,
+    /// The number of events that can wait for a plugin to handle them before we stop syncing,
+    /// which also bounds the events an ordered plugin has yet to handle in each room
+    #[serde(default = "default_dispatch_queue_size")]
+    pub queue_size: usize,
+    /// The number of seconds a plugin may take to handle an event
+    #[serde(default = "default_dispatch_timeout")]
+    pub timeout: u64,
+    /// Timeouts of individual plugins, by their type name, e.g. `GoogleSearchPlugin = 10`
+    #[serde(default)]
+    pub plugin_timeouts: HashMap<String, u64>,
+    /// The configuration for showing us as typing while plugins work
+    #[serde(default)]
+    pub typing: TypingConfig,
+}
+
+/// The configuration for showing us as typing while plugins handle text messages
+#[derive(Clone, Debug, Deserialize)]
+pub struct TypingConfig {
+    /// Whether to show us as typing at all
+    #[serde(default = "default_typing_enabled")]
+    pub enabled: bool,
+    /// The number of milliseconds a plugin may work before we show us as typing
+    #[serde(default = "default_typing_threshold")]
+    pub threshold: u64,
+    /// Whether to show us as typing for individual plugins, by their type name, e.g.
+    /// `UrlPreviewPlugin = true`
+    #[serde(default)]
+    pub plugins: HashMap<String, bool>,
+}
+
+impl Default for TypingConfig {
+    fn default() -> TypingConfig {
+        TypingConfig {
+            enabled: default_typing_enabled(),
+            threshold: default_typing_threshold(),
+            plugins: HashMap::new(),
+        }
+    }
+}
+
+impl Default for DispatchConfig {
+    fn default() -> DispatchConfig {
+        DispatchConfig {
+            workers: default_dispatch_workers(),
+            queue_size: default_dispatch_queue_size(),
+            timeout: default_dispatch_timeout(),
+            plugin_timeouts: HashMap::new(),
+            typing: TypingConfig::default(),
+        }
+    }
+}
+
+/// Which messages to send read receipts for
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptPolicy {
+    /// Don't send read receipts
+    None,
+    /// Send read receipts for every message we see
+    All,
+    /// Send read receipts for messages that a plugin replied or reacted to
+    Handled,
+}
+
+/// The configuration for read receipts and reactions to commands
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct ReceiptsConfig {
+    /// Which messages to send read receipts for
+    #[serde(default = "default_receipt_policy")]
+    pub policy: ReceiptPolicy,
+    /// Whether to react with ✅ or ❌ to commands once they've been handled, instead of replying
+    /// with an error message when they fail
+    #[serde(default)]
+    pub reactions: bool,
+}
+
+impl Default for ReceiptsConfig {
+    fn default() -> ReceiptsConfig {
+        ReceiptsConfig {
+            policy: default_receipt_policy(),
+            reactions: false,
+        }
+    }
+}
+
+/// The configuration for the recent messages that are kept for plugins
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct HistoryConfig {
+    /// The number of recent messages to keep in memory for each room
+    #[serde(default = "default_history_size")]
+    pub size: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> HistoryConfig {
+        HistoryConfig {
+            size: default_history_size(),
+        }
+    }
+}
+
+/// The configuration for the metrics endpoint
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct MetricsConfig {
+    /// The address to serve Prometheus metrics on at `/metrics`, e.g. `127.0.0.1:9100`
+    ///
+    /// Metrics aren't served if this isn't set
+    pub listen: Option<SocketAddr>,
+}
+
+/// The configuration for the health check endpoints
+#[derive(Clone, Debug, Deserialize)]
+pub struct HealthConfig {
+    /// The address to serve `/healthz` and `/readyz` on, e.g. `127.0.0.1:9101`
+    ///
+    /// Health checks aren't served if this isn't set
+    pub listen: Option<SocketAddr>,
+    /// The number of seconds since the last successful sync after which we're unhealthy
+    #[serde(default = "default_health_max_sync_age")]
+    pub max_sync_age: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> HealthConfig {
+        HealthConfig {
+            listen: None,
+            max_sync_age: default_health_max_sync_age(),
+        }
+    }
+}
+
+/// The configuration for end-to-end encryption
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct EncryptionConfig {
+    /// The passphrase that room key exports are encrypted with
+    ///
+    /// Exporting and importing room keys is disabled if this isn't set
+    pub key_export_passphrase: Option<String>,
 }
 
 /// The matrix-specific configuration
@@ -27,6 +327,332 @@ pub struct MatrixConfig {
     ///
     /// NOTE: this has to be room ids and not aliases
     pub rooms: Vec<String>,
+    /// Which room invites to accept
+    #[serde(default)]
+    pub invites: InviteConfig,
+    /// The user ids that may administer the bot, e.g. by verifying its device
+    #[serde(default)]
+    pub admins: Vec<String>,
+}
+
+/// Who the bot accepts room invites from
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvitePolicy {
+    /// Leave invites alone, neither accepting nor rejecting them
+    Ignore,
+    /// Reject all invites
+    Nobody,
+    /// Accept invites from anyone
+    Anyone,
+    /// Accept invites from the allowed users, and from users on the allowed homeservers
+    Allowlist,
+}
+
+/// The configuration for accepting room invites
+#[derive(Clone, Debug, Deserialize)]
+pub struct InviteConfig {
+    /// Who to accept invites from, ignoring all invites by default
+    #[serde(default = "default_invite_policy")]
+    pub policy: InvitePolicy,
+    /// The user ids to accept invites from when the policy is `allowlist`
+    #[serde(default)]
+    pub allowed_users: Vec<String>,
+    /// The homeservers whose users to accept invites from when the policy is `allowlist`
+    #[serde(default)]
+    pub allowed_homeservers: Vec<String>,
+}
+
+impl Default for InviteConfig {
+    fn default() -> InviteConfig {
+        InviteConfig {
+            policy: default_invite_policy(),
+            allowed_users: vec![],
+            allowed_homeservers: vec![],
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig {
+            user_agent: default_http_user_agent(),
+            timeout: default_http_timeout(),
+            connect_timeout: default_http_connect_timeout(),
+            max_body_size: default_http_max_body_size(),
+            max_redirects: default_http_max_redirects(),
+            allow_private_addresses: false,
+        }
+    }
+}
+
+/// The configuration for caching of HTTP responses and search results
+#[derive(Clone, Debug, Deserialize)]
+pub struct CacheConfig {
+    /// The number of seconds an entry is kept in the cache
+    #[serde(default = "default_cache_ttl")]
+    pub ttl: u64,
+    /// The maximum number of entries in each cache
+    ///
+    /// Setting this to 0 disables caching
+    #[serde(default = "default_cache_max_entries")]
+    pub max_entries: usize,
+    /// The maximum total size in bytes of the entries in each cache
+    #[serde(default = "default_cache_max_bytes")]
+    pub max_bytes: usize,
+    /// Keep cached entries in storage so they survive restarts
+    #[serde(default)]
+    pub persist: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            ttl: default_cache_ttl(),
+            max_entries: default_cache_max_entries(),
+            max_bytes: default_cache_max_bytes(),
+            persist: false,
+        }
+    }
+}
+
+/// Plugin-specific configuration
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PluginsConfig {
+    /// The configuration for the URL preview plugin
+    #[serde(default)]
+    pub url_preview: UrlPreviewConfig,
+    /// The configuration for the poll plugin
+    #[serde(default)]
+    pub poll: PollConfig,
+    /// The configuration for the greeter plugin
+    #[serde(default)]
+    pub greeter: GreeterConfig,
+    /// The configuration for the sed plugin
+    #[serde(default)]
+    pub sed: SedConfig,
+    /// The configuration for the karma plugin
+    #[serde(default)]
+    pub karma: KarmaConfig,
+}
+
+/// The configuration for the URL preview plugin
+#[derive(Clone, Debug, Deserialize)]
+pub struct UrlPreviewConfig {
+    /// Hosts to never fetch previews for, including their subdomains
+    #[serde(default)]
+    pub ignored_hosts: Vec<String>,
+    /// The maximum number of bytes to read from a page
+    #[serde(default = "default_url_preview_max_body_size")]
+    pub max_body_size: usize,
+    /// The maximum number of seconds to spend fetching a page
+    #[serde(default = "default_url_preview_timeout")]
+    pub timeout: u64,
+    /// The maximum number of URLs to preview in a single message
+    #[serde(default = "default_url_preview_max_urls")]
+    pub max_urls: usize,
+}
+
+impl Default for UrlPreviewConfig {
+    fn default() -> UrlPreviewConfig {
+        UrlPreviewConfig {
+            ignored_hosts: vec![],
+            max_body_size: default_url_preview_max_body_size(),
+            timeout: default_url_preview_timeout(),
+            max_urls: default_url_preview_max_urls(),
+        }
+    }
+}
+
+/// The configuration for the poll plugin
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PollConfig {
+    /// Send polls as MSC3381 poll events that supporting clients render natively, instead of
+    /// messages that are voted on with reactions
+    #[serde(default)]
+    pub native: bool,
+}
+
+/// The configuration for the greeter plugin
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct GreeterConfig {
+    /// The message to welcome new members with, by room id
+    ///
+    /// `{user}` is replaced with the display name of the new member, or their user id
+    #[serde(default)]
+    pub messages: HashMap<String, String>,
+}
+
+/// The configuration for the sed plugin
+#[derive(Clone, Debug, Deserialize)]
+pub struct SedConfig {
+    /// The number of recent messages to look through for one to correct
+    #[serde(default = "default_sed_max_messages")]
+    pub max_messages: usize,
+    /// The maximum size in bytes of a compiled pattern
+    #[serde(default = "default_sed_max_regex_size")]
+    pub max_regex_size: usize,
+    /// The maximum number of milliseconds to spend finding and correcting a message
+    #[serde(default = "default_sed_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for SedConfig {
+    fn default() -> SedConfig {
+        SedConfig {
+            max_messages: default_sed_max_messages(),
+            max_regex_size: default_sed_max_regex_size(),
+            timeout: default_sed_timeout(),
+        }
+    }
+}
+
+/// The configuration for the karma plugin
+#[derive(Clone, Debug, Deserialize)]
+pub struct KarmaConfig {
+    /// The number of seconds someone has to wait between giving or taking karma
+    #[serde(default = "default_karma_cooldown")]
+    pub cooldown: u64,
+}
+
+impl Default for KarmaConfig {
+    fn default() -> KarmaConfig {
+        KarmaConfig {
+            cooldown: default_karma_cooldown(),
+        }
+    }
+}
+
+fn default_invite_policy() -> InvitePolicy {
+    InvitePolicy::Ignore
+}
+
+fn default_http_user_agent() -> String {
+    "Mozilla/5.0 (X11; Linux x86_64; rv:80.0) Gecko/20100101 Firefox/80.0".to_string()
+}
+
+fn default_http_timeout() -> u64 {
+    10
+}
+
+fn default_http_connect_timeout() -> u64 {
+    5
+}
+
+fn default_http_max_body_size() -> usize {
+    2 * 1024 * 1024
+}
+
+fn default_http_max_redirects() -> usize {
+    5
+}
+
+fn default_log_format() -> LogFormat {
+    LogFormat::Pretty
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_sync_stall_timeout() -> u64 {
+    120
+}
+
+fn default_sync_initial_backoff() -> u64 {
+    1
+}
+
+fn default_sync_max_backoff() -> u64 {
+    300
+}
+
+fn default_sync_catch_up() -> CatchUpPolicy {
+    CatchUpPolicy::Skip
+}
+
+fn default_sync_catch_up_max_age() -> u64 {
+    30
+}
+
+fn default_sync_max_retries() -> u32 {
+    10
+}
+
+fn default_dispatch_workers() -> usize {
+    16
+}
+
+fn default_dispatch_queue_size() -> usize {
+    256
+}
+
+fn default_dispatch_timeout() -> u64 {
+    60
+}
+
+fn default_typing_enabled() -> bool {
+    true
+}
+
+fn default_typing_threshold() -> u64 {
+    1000
+}
+
+fn default_receipt_policy() -> ReceiptPolicy {
+    ReceiptPolicy::All
+}
+
+fn default_history_size() -> usize {
+    100
+}
+
+fn default_health_max_sync_age() -> u64 {
+    300
+}
+
+fn default_cache_ttl() -> u64 {
+    3600
+}
+
+fn default_cache_max_entries() -> usize {
+    1000
+}
+
+fn default_cache_max_bytes() -> usize {
+    32 * 1024 * 1024
+}
+
+fn default_url_preview_max_body_size() -> usize {
+    1024 * 1024
+}
+
+fn default_url_preview_timeout() -> u64 {
+    10
+}
+
+fn default_url_preview_max_urls() -> usize {
+    3
+}
+
+fn default_sed_max_messages() -> usize {
+    50
+}
+
+fn default_sed_max_regex_size() -> usize {
+    100 * 1024
+}
+
+fn default_sed_timeout() -> u64 {
+    500
+}
+
+fn default_karma_cooldown() -> u64 {
+    60
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from("data")
 }
 
 pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
@@ -0,0 +1,776 @@
+//! The context that is handed to plugins along with each incoming message
+
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, warn};
+use matrix_sdk::{
+    api::r0::{redact::redact_event, room::get_room_event, typing::create_typing_event::Typing},
+    events::{
+        custom::CustomEventContent,
+        room::{
+            message::{MessageEventContent, TextMessageEventContent},
+            relationships::{Relation, Replacement},
+        },
+        AnyMessageEventContent,
+    },
+    Client,
+};
+use matrix_sdk_common::identifiers::{EventId, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use tokio::sync::Mutex;
+
+use crate::metrics;
+use crate::plugin::Plugin;
+use crate::reaction::{ButtonMap, Reaction};
+use crate::storage::{Storage, StorageError};
+
+/// The number of days to remember replies for
+const MAX_REPLY_AGE_DAYS: i64 = 30;
+
+/// The relation type of messages in threads
+const THREAD_REL_TYPE: &str = "m.thread";
+
+/// Our replies to a single message
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Replies {
+    /// The room the message and replies were sent in
+    pub room_id: RoomId,
+    /// Our replies, in the order they were sent
+    pub event_ids: Vec<EventId>,
+    /// When the first reply was sent
+    pub sent_at: DateTime<Utc>,
+}
+
+/// Keeps track of which of our messages were sent in reply to which event
+///
+/// The mapping is persisted, so replies can still be edited or redacted after a restart.
+pub struct ReplyMap {
+    storage: Storage,
+}
+
+impl ReplyMap {
+    pub fn new(storage: Storage) -> ReplyMap {
+        ReplyMap { storage }
+    }
+
+    /// Returns our replies to `event_id`, if any
+    pub async fn get(&self, event_id: &EventId) -> Result<Option<Replies>, StorageError> {
+        self.storage.get(event_id.as_str()).await
+    }
+
+    /// Remembers that `reply` was sent in `room_id` in reply to `event_id`
+    pub async fn insert(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        reply: EventId,
+    ) -> Result<(), StorageError> {
+        let room_id = room_id.clone();
+
+        self.storage
+            .update(event_id.as_str(), move |replies: Option<Replies>| {
+                let mut replies = replies.unwrap_or_else(|| Replies {
+                    room_id,
+                    event_ids: vec![],
+                    sent_at: Utc::now(),
+                });
+
+                replies.event_ids.push(reply);
+
+                Some(replies)
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Forgets our replies to `event_id`, returning them
+    pub async fn remove(&self, event_id: &EventId) -> Result<Option<Replies>, StorageError> {
+        let replies = self.get(event_id).await?;
+
+        self.storage.delete(event_id.as_str()).await?;
+
+        Ok(replies)
+    }
+
+    /// Forgets replies that are too old to be worth editing or redacting
+    pub async fn prune(&self) -> Result<usize, StorageError> {
+        let oldest = Utc::now() - Duration::days(MAX_REPLY_AGE_DAYS);
+        let mut pruned = 0;
+
+        for (key, replies) in self.storage.list::<Replies>("").await? {
+            if replies.sent_at < oldest && self.storage.delete(&key).await? {
+                pruned += 1;
+            }
+        }
+
+        Ok(pruned)
+    }
+}
+
+/// What plugins did with a message, shared by all the plugins that handle it
+#[derive(Default)]
+struct Outcome {
+    /// Whether a plugin replied or reacted to the message
+    handled: AtomicBool,
+    /// Whether a command in the message succeeded
+    succeeded: AtomicBool,
+    /// Whether a command in the message failed
+    failed: AtomicBool,
+}
+
+/// Information about an incoming message, and a way to reply to it
+#[derive(Clone)]
+pub struct MessageContext {
+    /// The room the message was sent in
+    pub room_id: RoomId,
+    /// The user that sent the message
+    pub sender: UserId,
+    /// The id of the message event
+    pub event_id: EventId,
+    /// The id of the original message, if this message is an edit of it
+    pub replaces: Option<EventId>,
+    client: Client,
+    replies: Arc<ReplyMap>,
+    buttons: Arc<ButtonMap>,
+    outcome: Arc<Outcome>,
+    /// The message that the message replies to, if any
+    in_reply_to: Option<EventId>,
+    /// The root of the thread the message was sent in, or `None` if it hasn't been looked up
+    thread: Arc<Mutex<Option<Option<EventId>>>>,
+    /// Whether failed commands are marked with a reaction instead of an error message
+    error_reactions: bool,
+    /// The number of plugins that show us as typing while they handle the message
+    typing: Arc<Mutex<usize>>,
+}
+
+/// Returns a `m.replace` edit of `previous` that changes its content to `content`
+///
+/// Only text messages can be edited, so any other content is returned unchanged
+fn replacement(previous: EventId, content: MessageEventContent) -> MessageEventContent {
+    match content {
+        MessageEventContent::Text(new_content) => {
+            // Clients that don't support edits show the fallback body
+            let mut fallback = match new_content.formatted {
+                Some(ref formatted) => TextMessageEventContent::html(
+                    format!("* {}", new_content.body),
+                    format!("* {}", formatted.body),
+                ),
+                None => TextMessageEventContent::plain(format!("* {}", new_content.body)),
+            };
+
+            fallback.relates_to = Some(Relation::Replacement(Replacement { event_id: previous }));
+            fallback.new_content = Some(Box::new(MessageEventContent::Text(new_content)));
+
+            MessageEventContent::Text(fallback)
+        }
+        content => content,
+    }
+}
+
+/// Returns the root of the thread that the event `event` was sent in, if any
+pub fn thread_root(event: &JsonValue) -> Option<EventId> {
+    let relation = event.pointer("/content/m.relates_to")?;
+
+    if relation.get("rel_type")?.as_str()? != THREAD_REL_TYPE {
+        return None;
+    }
+
+    EventId::try_from(relation.get("event_id")?.as_str()?).ok()
+}
+
+/// Returns `content` as a message in the thread `root`, which is a reply to `event_id` for
+/// clients that don't support threads
+fn in_thread(
+    content: MessageEventContent,
+    root: &EventId,
+    event_id: &EventId,
+) -> AnyMessageEventContent {
+    let mut json = match serde_json::to_value(&content) {
+        Ok(json) => json,
+        Err(_) => return AnyMessageEventContent::RoomMessage(content),
+    };
+
+    // The SDK doesn't know about threads, so this is sent as a custom event of the same type
+    json["m.relates_to"] = json!({
+        "rel_type": THREAD_REL_TYPE,
+        "event_id": root,
+        "is_falling_back": true,
+        "m.in_reply_to": { "event_id": event_id },
+    });
+
+    AnyMessageEventContent::Custom(CustomEventContent {
+        event_type: "m.room.message".to_string(),
+        json,
+    })
+}
+
+/// Sends `content` to `room_id`, counting the result in the metrics
+///
+/// Every event we send to a room goes through here, or through [`redact`].
+pub async fn room_send(
+    client: &Client,
+    room_id: &RoomId,
+    content: AnyMessageEventContent,
+) -> Result<EventId, matrix_sdk::Error> {
+    let response = client.room_send(room_id, content, None).await;
+
+    metrics::observe_send(&response);
+
+    Ok(response?.event_id)
+}
+
+/// Redacts the event `event_id` in `room_id`, counting the result in the metrics
+///
+/// The redaction is only applied once per transaction id `txn_id`.
+pub async fn redact(
+    client: &Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+    txn_id: &str,
+    reason: &str,
+) -> Result<(), matrix_sdk::Error> {
+    let mut request = redact_event::Request::new(room_id, event_id, txn_id);
+    request.reason = Some(reason);
+
+    let response = client.send(request).await;
+
+    metrics::observe_send(&response);
+    response?;
+
+    Ok(())
+}
+
+/// Returns the body of a message without the quote of the message it replies to, which replies
+/// start with for clients that don't support replies
+pub fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+
+    let mut rest = body;
+
+    while rest.starts_with('>') {
+        rest = match rest.find('\n') {
+            Some(end) => &rest[end + 1..],
+            None => "",
+        };
+    }
+
+    rest.strip_prefix('\n').unwrap_or(rest)
+}
+
+impl MessageContext {
+    pub fn new(
+        client: Client,
+        replies: Arc<ReplyMap>,
+        buttons: Arc<ButtonMap>,
+        room_id: RoomId,
+        sender: UserId,
+        event_id: EventId,
+        replaces: Option<EventId>,
+    ) -> MessageContext {
+        MessageContext {
+            room_id,
+            sender,
+            event_id,
+            replaces,
+            client,
+            replies,
+            buttons,
+            outcome: Arc::new(Outcome::default()),
+            in_reply_to: None,
+            thread: Arc::new(Mutex::new(None)),
+            error_reactions: false,
+            typing: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// Marks failed commands with a ❌ reaction instead of replying with an error message
+    pub fn with_error_reactions(mut self, error_reactions: bool) -> MessageContext {
+        self.error_reactions = error_reactions;
+        self
+    }
+
+    /// Returns whether a plugin has replied or reacted to the message
+    pub fn was_handled(&self) -> bool {
+        self.outcome.handled.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether a command in the message succeeded, failed, or neither
+    pub fn command_result(&self) -> Option<bool> {
+        if self.outcome.failed.load(Ordering::SeqCst) {
+            Some(false)
+        } else if self.outcome.succeeded.load(Ordering::SeqCst) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    /// Records that the message was a command that succeeded
+    pub fn command_succeeded(&self) {
+        self.outcome.succeeded.store(true, Ordering::SeqCst);
+    }
+
+    /// Records that the message was a command that failed with `err`, and replies with the error
+    /// unless failed commands are marked with a reaction
+    pub async fn reply_error<E: Display>(&self, err: E) -> Result<(), matrix_sdk::Error> {
+        self.outcome.failed.store(true, Ordering::SeqCst);
+
+        if !self.error_reactions {
+            self.reply_text(format!("Error: {}", err)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Replies with the result of a command, see [`reply_error`] for how errors are replied to
+    ///
+    /// [`reply_error`]: #method.reply_error
+    pub async fn reply_result<E: Display>(
+        &self,
+        result: Result<String, E>,
+    ) -> Result<(), matrix_sdk::Error> {
+        match result {
+            Ok(reply) => {
+                self.command_succeeded();
+                self.reply_text(reply).await?;
+
+                Ok(())
+            }
+            Err(err) => self.reply_error(err).await,
+        }
+    }
+
+    /// Returns a span for log lines written while `plugin` handles the message
+    pub fn span(&self, plugin: &str) -> tracing::Span {
+        tracing::info_span!(
+            "event",
+            plugin,
+            room_id = %self.room_id,
+            sender = %self.sender,
+            event_id = %self.event_id,
+        )
+    }
+
+    /// Returns whether the message is an edit of an earlier message
+    pub fn is_edit(&self) -> bool {
+        self.replaces.is_some()
+    }
+
+    /// Returns the id of the message as it was first sent, before any edits
+    pub fn original_event_id(&self) -> &EventId {
+        self.replaces.as_ref().unwrap_or(&self.event_id)
+    }
+
+    /// Sets the message that the message replies to
+    ///
+    /// Messages in threads fall back to being replies for clients that don't support threads,
+    /// which includes the Matrix SDK, so only replies can be in threads.
+    pub fn with_in_reply_to(mut self, in_reply_to: Option<EventId>) -> MessageContext {
+        self.in_reply_to = in_reply_to;
+        self
+    }
+
+    /// Returns the message that the message replies to, if any
+    pub fn in_reply_to(&self) -> Option<&EventId> {
+        self.in_reply_to.as_ref()
+    }
+
+    /// Returns the root of the thread the message was sent in, if any
+    ///
+    /// The SDK doesn't tell us about threads, so the message is looked up on the homeserver the
+    /// first time this is called for a reply. Since [`reply`] calls this, replying to a message
+    /// that is itself a reply costs an extra request. The other messages in the thread can be
+    /// fetched with [`History::fetch_thread`].
+    ///
+    /// [`reply`]: #method.reply
+    /// [`History::fetch_thread`]: ../history/struct.History.html#method.fetch_thread
+    pub async fn thread(&self) -> Option<EventId> {
+        if self.in_reply_to.is_none() {
+            return None;
+        }
+
+        let mut thread = self.thread.lock().await;
+
+        if let Some(ref root) = *thread {
+            return root.clone();
+        }
+
+        let root = match self.room_event(&self.event_id).await {
+            Ok(event) => thread_root(&event),
+            Err(err) => {
+                warn!("Failed to look up the thread of {}: {}", self.event_id, err);
+
+                return None;
+            }
+        };
+
+        *thread = Some(root.clone());
+
+        root
+    }
+
+    /// Returns the event `event_id` from the room as JSON, e.g. the root of a thread
+    pub async fn room_event(&self, event_id: &EventId) -> Result<JsonValue, matrix_sdk::Error> {
+        let request = get_room_event::Request::new(&self.room_id, event_id);
+        let response = self.client.send(request).await?;
+
+        Ok(serde_json::to_value(&response.event)?)
+    }
+
+    /// Sends `content` to the room in reply to the message
+    ///
+    /// If the message is an edit and we already replied to the original message, our earlier
+    /// reply is edited instead of sending a new one. If the message was sent in a thread, the
+    /// reply is sent in the same thread, which takes looking up the message first if it's a
+    /// reply, see [`thread`].
+    ///
+    /// [`thread`]: #method.thread
+    pub async fn reply(&self, content: MessageEventContent) -> Result<EventId, matrix_sdk::Error> {
+        let previous_reply = match self.replaces {
+            Some(ref original) => match self.replies.get(original).await {
+                Ok(replies) => replies.and_then(|replies| replies.event_ids.into_iter().next()),
+                Err(err) => {
+                    warn!("Failed to look up our reply to {}: {}", original, err);
+
+                    None
+                }
+            },
+            None => None,
+        };
+        let content = match previous_reply {
+            Some(ref previous_reply) => {
+                AnyMessageEventContent::RoomMessage(replacement(previous_reply.clone(), content))
+            }
+            None => match self.thread().await {
+                Some(root) => in_thread(content, &root, &self.event_id),
+                None => AnyMessageEventContent::RoomMessage(content),
+            },
+        };
+
+        let event_id = room_send(&self.client, &self.room_id, content).await?;
+
+        self.outcome.handled.store(true, Ordering::SeqCst);
+
+        // We're done typing once the reply is there, even if plugins are still working
+        let mut typing = self.typing.lock().await;
+
+        if *typing > 0 {
+            *typing = 0;
+
+            if let Err(err) = self.send_typing(Typing::No).await {
+                debug!("Failed to stop typing in {}: {}", self.room_id, err);
+            }
+        }
+
+        drop(typing);
+
+        // Edits of our reply are redacted along with the reply itself, so they aren't kept
+        if previous_reply.is_none() {
+            if let Err(err) = self
+                .replies
+                .insert(&self.room_id, self.original_event_id(), event_id.clone())
+                .await
+            {
+                warn!("Failed to remember our reply to {}: {}", self.event_id, err);
+            }
+        }
+
+        Ok(event_id)
+    }
+
+    /// Sends a plain text message in reply to the message
+    pub async fn reply_text<S: Into<String>>(&self, body: S) -> Result<EventId, matrix_sdk::Error> {
+        let content = TextMessageEventContent::plain(body.into());
+
+        self.reply(MessageEventContent::Text(content)).await
+    }
+
+    /// Sends a text message with an html body in reply to the message
+    pub async fn reply_html<S: Into<String>, H: Into<String>>(
+        &self,
+        body: S,
+        html: H,
+    ) -> Result<EventId, matrix_sdk::Error> {
+        let content = TextMessageEventContent::html(body.into(), html.into());
+
+        self.reply(MessageEventContent::Text(content)).await
+    }
+
+    /// Reacts to the message with `key`
+    pub async fn react<S: Into<String>>(&self, key: S) -> Result<EventId, matrix_sdk::Error> {
+        let reaction = Reaction::new(self.event_id.clone(), key);
+        let event_id = room_send(&self.client, &self.room_id, reaction.to_content()).await?;
+
+        self.outcome.handled.store(true, Ordering::SeqCst);
+
+        Ok(event_id)
+    }
+
+    /// Marks the message as read by us
+    pub async fn send_read_receipt(&self) -> Result<(), matrix_sdk::Error> {
+        self.client
+            .read_receipt(&self.room_id, &self.event_id)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Shows us as typing in the room, until `timeout` has passed or every plugin that started
+    /// typing for the message has stopped
+    pub async fn start_typing(&self, timeout: StdDuration) -> Result<(), matrix_sdk::Error> {
+        let mut typing = self.typing.lock().await;
+
+        *typing += 1;
+
+        if *typing == 1 {
+            self.send_typing(Typing::Yes(timeout)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Stops showing us as typing in the room, unless other plugins are still typing for the
+    /// message
+    ///
+    /// Replying stops typing as well, in which case this does nothing.
+    pub async fn stop_typing(&self) -> Result<(), matrix_sdk::Error> {
+        let mut typing = self.typing.lock().await;
+
+        if *typing == 0 {
+            return Ok(());
+        }
+
+        *typing -= 1;
+
+        if *typing == 0 {
+            self.send_typing(Typing::No).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send_typing(&self, typing: Typing) -> Result<(), matrix_sdk::Error> {
+        let user_id = match self.client.user_id().await {
+            Some(user_id) => user_id,
+            None => return Ok(()),
+        };
+
+        self.client
+            .typing_notice(&self.room_id, &user_id, typing)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Sends `content` in reply to the message, with a reaction for each of `keys` that users
+    /// can click on like a button
+    ///
+    /// When a user reacts with one of `keys`, the `on_button` hook of `plugin` is called.
+    pub async fn reply_with_buttons<P: Plugin + ?Sized>(
+        &self,
+        plugin: &P,
+        content: MessageEventContent,
+        keys: &[&str],
+    ) -> Result<EventId, matrix_sdk::Error> {
+        let event_id = self.reply(content).await?;
+
+        if let Err(err) = self.buttons.insert(&event_id, plugin.name(), keys).await {
+            warn!("Failed to remember the buttons of {}: {}", event_id, err);
+        }
+
+        for key in keys {
+            let reaction = Reaction::new(event_id.clone(), *key);
+
+            room_send(&self.client, &self.room_id, reaction.to_content()).await?;
+        }
+
+        Ok(event_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+    use std::convert::TryFrom;
+
+    fn event_id(id: &str) -> EventId {
+        EventId::try_from(id).unwrap()
+    }
+
+    #[test]
+    fn test_strip_reply_fallback() {
+        assert_eq!(strip_reply_fallback(".quote add"), ".quote add");
+        assert_eq!(
+            strip_reply_fallback("> <@alice:example.com> first\n> second\n\n.quote add"),
+            ".quote add"
+        );
+        assert_eq!(strip_reply_fallback("> <@alice:example.com> hi"), "");
+        assert_eq!(strip_reply_fallback(">_>"), ">_>");
+    }
+
+    #[test]
+    fn test_thread() {
+        let root = event_id("$root:example.com");
+        let event = json!({
+            "type": "m.room.message",
+            "content": {
+                "msgtype": "m.text",
+                "body": ".g rust",
+                "m.relates_to": {
+                    "rel_type": "m.thread",
+                    "event_id": "$root:example.com",
+                    "is_falling_back": true,
+                    "m.in_reply_to": { "event_id": "$previous:example.com" },
+                },
+            },
+        });
+
+        assert_eq!(thread_root(&event), Some(root.clone()));
+        assert_eq!(thread_root(&json!({ "content": { "body": "hi" } })), None);
+
+        let content = MessageEventContent::Text(TextMessageEventContent::plain("Rust"));
+
+        match in_thread(content, &root, &event_id("$command:example.com")) {
+            AnyMessageEventContent::Custom(content) => {
+                assert_eq!(content.event_type, "m.room.message");
+                assert_eq!(content.json["body"], "Rust");
+                assert_eq!(content.json["m.relates_to"]["rel_type"], "m.thread");
+                assert_eq!(
+                    content.json["m.relates_to"]["event_id"],
+                    "$root:example.com"
+                );
+                assert_eq!(
+                    content.json["m.relates_to"]["m.in_reply_to"]["event_id"],
+                    "$command:example.com"
+                );
+            }
+            _ => panic!("expected a custom event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_result() {
+        let client = Client::new(url::Url::parse("http://example.com").unwrap()).unwrap();
+        let storage = Storage::new(Arc::new(MemoryBackend::new()), "test");
+        let ctx = MessageContext::new(
+            client,
+            Arc::new(ReplyMap::new(storage.clone())),
+            Arc::new(ButtonMap::new(storage)),
+            RoomId::try_from("!room:example.com").unwrap(),
+            UserId::try_from("@alice:example.com").unwrap(),
+            event_id("$command:example.com"),
+            None,
+        )
+        .with_error_reactions(true);
+
+        assert_eq!(ctx.command_result(), None);
+        ctx.command_succeeded();
+        assert_eq!(ctx.command_result(), Some(true));
+
+        // The error is only marked with a reaction, so nothing is sent yet
+        ctx.reply_error("no such poll").await.unwrap();
+        assert_eq!(ctx.command_result(), Some(false));
+        assert!(!ctx.was_handled());
+    }
+
+    #[tokio::test]
+    async fn test_typing() {
+        let client = Client::new(url::Url::parse("http://example.com").unwrap()).unwrap();
+        let storage = Storage::new(Arc::new(MemoryBackend::new()), "test");
+        let ctx = MessageContext::new(
+            client,
+            Arc::new(ReplyMap::new(storage.clone())),
+            Arc::new(ButtonMap::new(storage)),
+            RoomId::try_from("!room:example.com").unwrap(),
+            UserId::try_from("@alice:example.com").unwrap(),
+            event_id("$command:example.com"),
+            None,
+        );
+        let timeout = StdDuration::from_secs(10);
+
+        // We aren't logged in, so nothing is sent
+        ctx.start_typing(timeout).await.unwrap();
+        ctx.clone().start_typing(timeout).await.unwrap();
+        ctx.stop_typing().await.unwrap();
+        assert_eq!(*ctx.typing.lock().await, 1);
+
+        ctx.stop_typing().await.unwrap();
+        ctx.stop_typing().await.unwrap();
+        assert_eq!(*ctx.typing.lock().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_reply_map() {
+        let storage = Storage::new(Arc::new(MemoryBackend::new()), "replies");
+        let replies = ReplyMap::new(storage.clone());
+        let room_id = RoomId::try_from("!room:example.com").unwrap();
+        let trigger = event_id("$trigger:example.com");
+
+        replies
+            .insert(&room_id, &trigger, event_id("$reply1:example.com"))
+            .await
+            .unwrap();
+        replies
+            .insert(&room_id, &trigger, event_id("$reply2:example.com"))
+            .await
+            .unwrap();
+
+        let sent = replies.get(&trigger).await.unwrap().unwrap();
+        assert_eq!(sent.room_id, room_id);
+        assert_eq!(
+            sent.event_ids,
+            vec![
+                event_id("$reply1:example.com"),
+                event_id("$reply2:example.com")
+            ]
+        );
+
+        // Replies are remembered across restarts
+        let restarted = ReplyMap::new(storage.clone());
+        assert_eq!(restarted.remove(&trigger).await.unwrap(), Some(sent));
+        assert_eq!(restarted.get(&trigger).await.unwrap(), None);
+
+        let old = Replies {
+            room_id,
+            event_ids: vec![event_id("$old_reply:example.com")],
+            sent_at: Utc::now() - Duration::days(MAX_REPLY_AGE_DAYS + 1),
+        };
+        storage.set("$old:example.com", &old).await.unwrap();
+        assert_eq!(restarted.prune().await.unwrap(), 1);
+        assert_eq!(
+            restarted.get(&event_id("$old:example.com")).await.unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_replacement() {
+        let content = MessageEventContent::Text(TextMessageEventContent::plain("new"));
+
+        match replacement(event_id("$reply:example.com"), content) {
+            MessageEventContent::Text(edit) => {
+                assert_eq!(edit.body, "* new");
+                assert!(matches!(
+                    edit.relates_to,
+                    Some(Relation::Replacement(Replacement { ref event_id }))
+                        if event_id.as_str() == "$reply:example.com"
+                ));
+                assert!(matches!(
+                    edit.new_content.as_deref(),
+                    Some(MessageEventContent::Text(new)) if new.body == "new"
+                ));
+            }
+            content => panic!("unexpected content: {:?}", content),
+        }
+    }
+}
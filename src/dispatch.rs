@@ -0,0 +1,520 @@
+//! Runs plugin hooks concurrently on a bounded pool of workers
+//!
+//! Every event is handed to each plugin as a separate job with its own timeout, so a slow plugin
+//! doesn't hold up the others or later events. Jobs wait in a bounded queue, and dispatching an
+//! event waits while the queue is full. Plugins that need to see the events of a room in order
+//! get them one at a time from a separate queue for each room, which has its own task, so that
+//! waiting for the previous event doesn't take up a worker. These queues are bounded as well.
+//!
+//! While a plugin takes long to handle a text message, which is likely a command, we show us as
+//! typing in the room.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, warn};
+use matrix_sdk::events::{
+    custom::CustomEventContent, room::message::MessageEventContent, AnyToDeviceEvent,
+};
+use matrix_sdk_common::identifiers::RoomId;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time;
+use tracing::{Instrument, Span};
+
+use crate::config::DispatchConfig;
+use crate::context::MessageContext;
+use crate::membership::Member;
+use crate::metrics;
+use crate::plugin::Plugin;
+use crate::reaction::Reaction;
+
+/// An event that can be dispatched to plugins
+pub enum Event {
+    /// A message in a room
+    Message(MessageContext, MessageEventContent),
+    /// A change of membership in a room
+    Member(MessageContext, Member),
+    /// A message event that the Matrix SDK doesn't know about
+    Custom(MessageContext, CustomEventContent),
+    /// A reaction to a message
+    Reaction(MessageContext, Reaction),
+    /// A click on one of the buttons of a message
+    Button(MessageContext, Reaction),
+    /// The removal of a click on one of the buttons of a message
+    ButtonReleased(MessageContext, Reaction),
+    /// A to-device event
+    ToDevice(AnyToDeviceEvent),
+}
+
+impl Event {
+    /// Returns the kind of event, for metrics
+    fn label(&self) -> &'static str {
+        match self {
+            Event::Message(_, content) => match content {
+                MessageEventContent::Audio(_) => "audio",
+                MessageEventContent::Emote(_) => "emote",
+                MessageEventContent::File(_) => "file",
+                MessageEventContent::Image(_) => "image",
+                MessageEventContent::Location(_) => "location",
+                MessageEventContent::Notice(_) => "notice",
+                MessageEventContent::ServerNotice(_) => "server_notice",
+                MessageEventContent::Text(_) => "text",
+                MessageEventContent::Video(_) => "video",
+                _ => "message",
+            },
+            Event::Member(..) => "member",
+            Event::Custom(..) => "custom",
+            Event::Reaction(..) => "reaction",
+            Event::Button(..) => "button",
+            Event::ButtonReleased(..) => "button_released",
+            Event::ToDevice(_) => "to_device",
+        }
+    }
+
+    /// Returns the room the event was sent in, if any
+    fn room_id(&self) -> Option<&RoomId> {
+        match self {
+            Event::Message(ctx, _)
+            | Event::Member(ctx, _)
+            | Event::Custom(ctx, _)
+            | Event::Reaction(ctx, _)
+            | Event::Button(ctx, _)
+            | Event::ButtonReleased(ctx, _) => Some(&ctx.room_id),
+            Event::ToDevice(_) => None,
+        }
+    }
+
+    /// Returns the context of the event if it's one that we show us as typing for, which are
+    /// text messages, since those are what commands are
+    fn typing_context(&self) -> Option<&MessageContext> {
+        match self {
+            Event::Message(ctx, MessageEventContent::Text(_)) => Some(ctx),
+            _ => None,
+        }
+    }
+
+    /// Returns the span that `plugin` handles the event in
+    fn span(&self, plugin: &str) -> Span {
+        match self {
+            Event::Message(ctx, _)
+            | Event::Member(ctx, _)
+            | Event::Custom(ctx, _)
+            | Event::Reaction(ctx, _)
+            | Event::Button(ctx, _)
+            | Event::ButtonReleased(ctx, _) => ctx.span(plugin),
+            Event::ToDevice(_) => tracing::info_span!("event", plugin),
+        }
+    }
+
+    /// Calls the hook of `plugin` for the event
+    async fn deliver(&self, plugin: &dyn Plugin) {
+        match self {
+            Event::Message(ctx, content) => match content {
+                MessageEventContent::Audio(content) => {
+                    plugin.on_room_audio_message(ctx, content).await
+                }
+                MessageEventContent::Emote(content) => {
+                    plugin.on_room_emote_message(ctx, content).await
+                }
+                MessageEventContent::File(content) => {
+                    plugin.on_room_file_message(ctx, content).await
+                }
+                MessageEventContent::Image(content) => {
+                    plugin.on_room_image_message(ctx, content).await
+                }
+                MessageEventContent::Location(content) => {
+                    plugin.on_room_location_message(ctx, content).await
+                }
+                MessageEventContent::Notice(content) => {
+                    plugin.on_room_notice_message(ctx, content).await
+                }
+                MessageEventContent::ServerNotice(content) => {
+                    plugin.on_room_server_notice_message(ctx, content).await
+                }
+                MessageEventContent::Text(content) => {
+                    plugin.on_room_text_message(ctx, content).await
+                }
+                MessageEventContent::Video(content) => {
+                    plugin.on_room_video_message(ctx, content).await
+                }
+                _ => {}
+            },
+            Event::Member(ctx, member) => plugin.on_room_member(ctx, member).await,
+            Event::Custom(ctx, content) => plugin.on_room_custom_event(ctx, content).await,
+            Event::Reaction(ctx, reaction) => plugin.on_reaction(ctx, reaction).await,
+            Event::Button(ctx, reaction) => plugin.on_button(ctx, reaction).await,
+            Event::ButtonReleased(ctx, reaction) => plugin.on_button_released(ctx, reaction).await,
+            Event::ToDevice(event) => plugin.on_to_device_event(event).await,
+        }
+    }
+}
+
+/// Runs `hook`, showing us as typing in the room of `ctx` if it takes longer than `threshold`
+///
+/// Typing is counted per message, so it only stops once every plugin that started it for the
+/// message is done, or one of them replies.
+async fn with_typing<F: Future>(
+    ctx: &MessageContext,
+    threshold: Duration,
+    timeout: Duration,
+    hook: F,
+) -> F::Output {
+    tokio::pin!(hook);
+
+    tokio::select! {
+        output = &mut hook => return output,
+        _ = time::delay_for(threshold) => {}
+    }
+
+    // Typing expires by itself in case we fail to stop it
+    if let Err(err) = ctx.start_typing(timeout).await {
+        debug!("Failed to start typing in {}: {}", ctx.room_id, err);
+    }
+
+    let output = hook.await;
+
+    if let Err(err) = ctx.stop_typing().await {
+        debug!("Failed to stop typing in {}: {}", ctx.room_id, err);
+    }
+
+    output
+}
+
+/// The events of a room that an ordered plugin handles one at a time
+type Lane = (&'static str, Option<RoomId>);
+
+/// The jobs of a lane that haven't finished yet
+struct LaneQueue {
+    jobs: mpsc::Sender<Job>,
+    /// The number of jobs that were queued and haven't finished yet
+    pending: usize,
+}
+
+type Lanes = Arc<std::sync::Mutex<HashMap<Lane, LaneQueue>>>;
+
+/// A plugin that is to handle an event
+struct Job {
+    plugin: Arc<dyn Plugin>,
+    event: Arc<Event>,
+    timeout: Duration,
+    /// How long the plugin may work before we show us as typing, if at all
+    typing: Option<Duration>,
+    /// Dropped when this job has finished, which completes the event once all its jobs have
+    finished: mpsc::Sender<()>,
+}
+
+impl Job {
+    async fn run(self) {
+        let name = self.plugin.name();
+        let label = self.event.label();
+        let labels = [name, label];
+        let timeout = self.timeout;
+        let typing = self.typing;
+        let plugin = self.plugin;
+        let event = self.event;
+
+        metrics::PLUGIN_EVENTS.with_label_values(&labels).inc();
+
+        // Running the hook in its own task means that a panic only takes down the hook
+        let handle = tokio::spawn(async move {
+            let timer = metrics::PLUGIN_HANDLER_DURATION
+                .with_label_values(&[plugin.name(), event.label()])
+                .start_timer();
+            let span = event.span(plugin.name());
+            let hook = time::timeout(timeout, event.deliver(plugin.as_ref()));
+            let result = match (typing, event.typing_context()) {
+                (Some(threshold), Some(ctx)) => {
+                    with_typing(ctx, threshold, timeout, hook)
+                        .instrument(span)
+                        .await
+                }
+                _ => hook.instrument(span).await,
+            };
+
+            timer.observe_duration();
+            result
+        });
+
+        match handle.await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => {
+                warn!(
+                    "Plugin {} took longer than {} seconds to handle a {} event",
+                    name,
+                    timeout.as_secs(),
+                    label
+                );
+                metrics::PLUGIN_FAILURES
+                    .with_label_values(&[name, "timeout"])
+                    .inc();
+            }
+            Err(err) => {
+                error!(
+                    "Plugin {} failed to handle a {} event: {}",
+                    name, label, err
+                );
+                metrics::PLUGIN_FAILURES
+                    .with_label_values(&[name, "panic"])
+                    .inc();
+            }
+        }
+
+        drop(self.finished);
+    }
+}
+
+/// Resolves once all plugins have finished handling a dispatched event
+pub struct Completion(mpsc::Receiver<()>);
+
+impl Completion {
+    /// Waits until all plugins have finished handling the event
+    pub async fn wait(mut self) {
+        // Nothing is ever sent, so this only returns once every job has dropped its sender
+        while self.0.recv().await.is_some() {}
+    }
+}
+
+/// Takes jobs off the queue and runs them, until the queue is closed
+async fn work(queue: Arc<Mutex<mpsc::Receiver<Job>>>) {
+    loop {
+        let job = match queue.lock().await.recv().await {
+            Some(job) => job,
+            None => return,
+        };
+
+        job.run().await;
+    }
+}
+
+/// Runs the jobs of `lane` one at a time, and forgets the lane once all its jobs have finished
+async fn run_lane(lane: Lane, mut jobs: mpsc::Receiver<Job>, lanes: Lanes) {
+    while let Some(job) = jobs.recv().await {
+        job.run().await;
+
+        let mut lanes = lanes.lock().unwrap();
+        let queue = lanes
+            .get_mut(&lane)
+            .expect("a lane is known until it has no jobs");
+
+        queue.pending -= 1;
+
+        if queue.pending == 0 {
+            lanes.remove(&lane);
+
+            return;
+        }
+    }
+}
+
+pub struct Dispatcher {
+    config: DispatchConfig,
+    queue: mpsc::Sender<Job>,
+    /// The lanes that have jobs which haven't finished yet
+    lanes: Lanes,
+}
+
+impl Dispatcher {
+    /// Returns a new dispatcher, and spawns its workers on the current runtime
+    pub fn new(config: DispatchConfig) -> Dispatcher {
+        let (sender, receiver) = mpsc::channel(config.queue_size.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..config.workers.max(1) {
+            tokio::spawn(work(receiver.clone()));
+        }
+
+        Dispatcher {
+            config,
+            queue: sender,
+            lanes: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns how long `plugin` may take to handle an event
+    fn timeout(&self, plugin: &dyn Plugin) -> Duration {
+        let secs = self
+            .config
+            .plugin_timeouts
+            .get(plugin.name())
+            .copied()
+            .unwrap_or(self.config.timeout);
+
+        Duration::from_secs(secs)
+    }
+
+    /// Returns how long `plugin` may work on a text message before we show us as typing, or
+    /// `None` if we shouldn't
+    fn typing_threshold(&self, plugin: &dyn Plugin) -> Option<Duration> {
+        let config = &self.config.typing;
+        let enabled = config.enabled
+            && config
+                .plugins
+                .get(plugin.name())
+                .copied()
+                .unwrap_or_else(|| plugin.shows_typing());
+
+        if enabled {
+            Some(Duration::from_millis(config.threshold))
+        } else {
+            None
+        }
+    }
+
+    /// Queues `job` behind the earlier jobs of `lane`, starting a task for the lane if it has
+    /// none, and waiting while the lane is full
+    async fn queue_in_lane(&self, lane: Lane, job: Job) {
+        let mut jobs = {
+            let mut lanes = self.lanes.lock().unwrap();
+            let queue = lanes.entry(lane.clone()).or_insert_with(|| {
+                let (sender, receiver) = mpsc::channel(self.config.queue_size.max(1));
+
+                tokio::spawn(run_lane(lane, receiver, self.lanes.clone()));
+
+                LaneQueue {
+                    jobs: sender,
+                    pending: 0,
+                }
+            });
+
+            // Counting the job before it's sent keeps the lane from being forgotten meanwhile
+            queue.pending += 1;
+            queue.jobs.clone()
+        };
+
+        if jobs.send(job).await.is_err() {
+            error!("Failed to queue an event, as its lane has stopped");
+        }
+    }
+
+    /// Queues `event` to be handled by each of `plugins`, waiting while the queue is full
+    ///
+    /// Jobs for ordered plugins are queued in their lane instead, waiting while it is full.
+    pub async fn dispatch(&self, event: Event, plugins: Vec<Arc<dyn Plugin>>) -> Completion {
+        let event = Arc::new(event);
+        let mut queue = self.queue.clone();
+        let (finished, completion) = mpsc::channel(1);
+
+        for plugin in plugins {
+            let ordered = plugin.ordered();
+            let job = Job {
+                timeout: self.timeout(plugin.as_ref()),
+                typing: self.typing_threshold(plugin.as_ref()),
+                plugin,
+                event: event.clone(),
+                finished: finished.clone(),
+            };
+
+            if ordered {
+                let lane = (job.plugin.name(), event.room_id().cloned());
+
+                self.queue_in_lane(lane, job).await;
+
+                continue;
+            }
+
+            if queue.send(job).await.is_err() {
+                error!("Failed to queue an event, as the dispatcher has stopped");
+
+                break;
+            }
+        }
+
+        Completion(completion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::ReplyMap;
+    use crate::plugin::PluginContext;
+    use crate::reaction::ButtonMap;
+    use crate::storage::{MemoryBackend, Storage};
+    use crate::Error;
+    use async_trait::async_trait;
+    use matrix_sdk::{events::room::message::TextMessageEventContent, Client};
+    use matrix_sdk_common::identifiers::{EventId, UserId};
+    use std::convert::TryFrom;
+
+    #[derive(Default)]
+    struct TestPlugin {
+        ordered: bool,
+        seen: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Plugin for TestPlugin {
+        fn new(_: PluginContext) -> Result<Self, Error> {
+            Ok(TestPlugin::default())
+        }
+
+        fn ordered(&self) -> bool {
+            self.ordered
+        }
+
+        async fn on_room_text_message(&self, _: &MessageContext, msg: &TextMessageEventContent) {
+            if msg.body == "slow" {
+                time::delay_for(Duration::from_millis(100)).await;
+            } else if msg.body == "stuck" {
+                time::delay_for(Duration::from_secs(60)).await;
+            }
+
+            self.seen.lock().unwrap().push(msg.body.clone());
+        }
+    }
+
+    fn text_event(client: &Client, body: &str) -> Event {
+        let storage = Storage::new(Arc::new(MemoryBackend::new()), "test");
+        let ctx = MessageContext::new(
+            client.clone(),
+            Arc::new(ReplyMap::new(storage.clone())),
+            Arc::new(ButtonMap::new(storage)),
+            RoomId::try_from("!room:example.com").unwrap(),
+            UserId::try_from("@alice:example.com").unwrap(),
+            EventId::try_from("$event:example.com").unwrap(),
+            None,
+        );
+
+        Event::Message(
+            ctx,
+            MessageEventContent::Text(TextMessageEventContent::plain(body)),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_dispatch() {
+        let client = Client::new(url::Url::parse("http://example.com").unwrap()).unwrap();
+        let config: DispatchConfig = toml::from_str("timeout = 1").unwrap();
+        let dispatcher = Dispatcher::new(config);
+        let ordered = Arc::new(TestPlugin {
+            ordered: true,
+            ..TestPlugin::default()
+        });
+        let unordered = Arc::new(TestPlugin::default());
+        let plugins: Vec<Arc<dyn Plugin>> = vec![ordered.clone(), unordered.clone()];
+
+        let mut completions = vec![];
+
+        for body in &["stuck", "slow", "fast"] {
+            let completion = dispatcher
+                .dispatch(text_event(&client, body), plugins.clone())
+                .await;
+
+            completions.push(completion);
+        }
+
+        for completion in completions {
+            completion.wait().await;
+        }
+
+        // The stuck event times out, and only the ordered plugin waits for the slow one
+        assert_eq!(*unordered.seen.lock().unwrap(), vec!["fast", "slow"]);
+        assert_eq!(*ordered.seen.lock().unwrap(), vec!["slow", "fast"]);
+
+        // The lane is forgotten right after its last job has finished
+        time::delay_for(Duration::from_millis(10)).await;
+        assert!(dispatcher.lanes.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,93 @@
+//! Parsing and formatting of short human-written durations such as `1h30m`
+
+use chrono::Duration;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    pub static ref DURATION: Regex = Regex::new(r"^(?:\d+[wdhms])+$").unwrap();
+    pub static ref DURATION_COMPONENT: Regex = Regex::new(r"(\d+)([wdhms])").unwrap();
+}
+
+/// Parses a positive duration such as `2h`, `1h30m` or `1w`
+///
+/// Returns `None` if the duration is too long to represent.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    if !DURATION.is_match(s) {
+        return None;
+    }
+
+    let mut total = Duration::zero();
+
+    for captures in DURATION_COMPONENT.captures_iter(s) {
+        let amount: i64 = captures[1].parse().ok()?;
+        let unit_secs = match &captures[2] {
+            "w" => 604_800,
+            "d" => 86_400,
+            "h" => 3_600,
+            "m" => 60,
+            _ => 1,
+        };
+        // chrono panics on durations that don't fit, and anyone can send us one
+        let millis = amount.checked_mul(unit_secs)?.checked_mul(1000)?;
+
+        total = total.checked_add(&Duration::milliseconds(millis))?;
+    }
+
+    if total <= Duration::zero() {
+        return None;
+    }
+
+    Some(total)
+}
+
+/// Formats a duration as the largest whole units, e.g. `1d2h`
+pub fn format_duration(duration: Duration) -> String {
+    let mut secs = duration.num_seconds();
+    let mut result = String::new();
+
+    for &(unit, size) in &[
+        ("w", 604_800),
+        ("d", 86_400),
+        ("h", 3_600),
+        ("m", 60),
+        ("s", 1),
+    ] {
+        if secs >= size {
+            result.push_str(&format!("{}{}", secs / size, unit));
+            secs %= size;
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("2h"), Some(Duration::hours(2)));
+        assert_eq!(
+            parse_duration("1h30m"),
+            Some(Duration::hours(1) + Duration::minutes(30))
+        );
+        assert_eq!(parse_duration("1w"), Some(Duration::weeks(1)));
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("soon"), None);
+        assert_eq!(parse_duration("2h foo"), None);
+        assert_eq!(parse_duration("99999999999999w"), None);
+        assert_eq!(parse_duration("9223372036854775807s"), None);
+        assert_eq!(parse_duration("60000000000d60000000000d"), None);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(
+            format_duration(Duration::days(1) + Duration::hours(2)),
+            "1d2h"
+        );
+        assert_eq!(format_duration(Duration::minutes(90)), "1h30m");
+    }
+}
@@ -18,6 +18,31 @@ pub enum Error {
     #[error("unable to parse homeserver url")]
     HomeserverParseError(#[from] url::ParseError),
 
-    #[error("could not build http client")]
-    ReqwestBuildError(#[from] reqwest::Error),
+    #[error("could not build shared http client")]
+    HttpError(#[from] crate::http::HttpError),
+
+    #[error("storage error")]
+    StorageError(#[from] crate::storage::StorageError),
+
+    /// Syncing with the homeserver failed too many times in a row
+    #[error("gave up syncing with the homeserver after {attempts} failed attempts: {last_error}")]
+    SyncFailed {
+        attempts: u32,
+        #[source]
+        last_error: SyncError,
+    },
+
+    /// An error that is specific to a plugin
+    #[error("plugin error: {0}")]
+    PluginError(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Why a sync with the homeserver failed
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error("{0}")]
+    MatrixError(#[from] matrix_sdk::Error),
+
+    #[error("no response within {0} seconds")]
+    Stalled(u64),
 }
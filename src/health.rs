@@ -0,0 +1,192 @@
+//! Health and readiness checks for process supervisors, served on an optional HTTP listener
+//!
+//! `/healthz` fails once the last successful sync is older than the configured maximum age, which
+//! catches a sync loop that is stuck while the process stays alive. `/readyz` additionally
+//! requires that login succeeded and that every plugin has finished starting.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::Serialize;
+
+use crate::config::HealthConfig;
+
+/// How far a plugin has come in starting
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PluginState {
+    Starting,
+    Started,
+    Failed,
+}
+
+struct State {
+    /// When the process started, which counts as the last sync until the first one succeeds
+    started_at: Instant,
+    /// When the last successful sync finished
+    last_sync: Option<Instant>,
+    /// Whether we're logged in to the homeserver
+    logged_in: bool,
+    /// The state of each plugin, by name
+    plugins: BTreeMap<&'static str, PluginState>,
+}
+
+/// The status that is reported by both endpoints
+#[derive(Debug, Serialize)]
+struct Report {
+    healthy: bool,
+    ready: bool,
+    logged_in: bool,
+    /// The number of seconds since the last successful sync, if there has been one
+    seconds_since_sync: Option<u64>,
+    plugins: BTreeMap<&'static str, PluginState>,
+}
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State {
+        started_at: Instant::now(),
+        last_sync: None,
+        logged_in: false,
+        plugins: BTreeMap::new(),
+    });
+}
+
+/// Records that we've logged in to the homeserver
+pub fn set_logged_in() {
+    STATE.lock().unwrap().logged_in = true;
+}
+
+/// Records that a sync with the homeserver has just succeeded
+pub fn record_sync() {
+    STATE.lock().unwrap().last_sync = Some(Instant::now());
+}
+
+/// Records how far the plugin `plugin` has come in starting
+pub fn set_plugin_state(plugin: &'static str, state: PluginState) {
+    STATE.lock().unwrap().plugins.insert(plugin, state);
+}
+
+impl State {
+    fn report(&self, max_sync_age: Duration, now: Instant) -> Report {
+        let since_sync = now - self.last_sync.unwrap_or(self.started_at);
+        let healthy = since_sync <= max_sync_age;
+        let ready = healthy
+            && self.logged_in
+            && self.last_sync.is_some()
+            && self
+                .plugins
+                .values()
+                .all(|state| *state != PluginState::Starting);
+
+        Report {
+            healthy,
+            ready,
+            logged_in: self.logged_in,
+            seconds_since_sync: self.last_sync.map(|_| since_sync.as_secs()),
+            plugins: self.plugins.clone(),
+        }
+    }
+}
+
+async fn handle(
+    request: Request<Body>,
+    max_sync_age: Duration,
+) -> Result<Response<Body>, Infallible> {
+    let report = STATE.lock().unwrap().report(max_sync_age, Instant::now());
+    let ok = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/healthz") => report.healthy,
+        (&Method::GET, "/readyz") => report.ready,
+        _ => {
+            let response = Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty());
+
+            return Ok(response.expect("responses are always valid"));
+        }
+    };
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = serde_json::to_vec(&report).expect("reports can always be serialized");
+    let response = Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body));
+
+    Ok(response.expect("responses are always valid"))
+}
+
+/// Binds to the configured address and returns a future that serves the health checks on it
+/// forever, or `None` if they're disabled
+pub fn serve(config: &HealthConfig) -> Result<Option<impl Future<Output = ()>>, hyper::Error> {
+    let addr = match config.listen {
+        Some(addr) => addr,
+        None => return Ok(None),
+    };
+    let max_sync_age = Duration::from_secs(config.max_sync_age);
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |request| handle(request, max_sync_age)))
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+
+    info!("Serving health checks on http://{}/healthz", addr);
+
+    Ok(Some(async move {
+        if let Err(err) = server.await {
+            error!("Health check listener failed: {}", err);
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let now = Instant::now();
+        let max_sync_age = Duration::from_secs(60);
+        let mut state = State {
+            started_at: now,
+            last_sync: None,
+            logged_in: false,
+            plugins: BTreeMap::new(),
+        };
+
+        state.plugins.insert("poll", PluginState::Starting);
+        state.plugins.insert("greeter", PluginState::Failed);
+
+        // Still starting up
+        let report = state.report(max_sync_age, now + Duration::from_secs(10));
+        assert!(report.healthy);
+        assert!(!report.ready);
+        assert_eq!(report.seconds_since_sync, None);
+
+        state.logged_in = true;
+        state.last_sync = Some(now + Duration::from_secs(20));
+        let report = state.report(max_sync_age, now + Duration::from_secs(30));
+        assert!(report.healthy);
+        assert!(!report.ready);
+        assert_eq!(report.seconds_since_sync, Some(10));
+
+        // Plugins that failed to start have still finished starting
+        state.plugins.insert("poll", PluginState::Started);
+        let report = state.report(max_sync_age, now + Duration::from_secs(30));
+        assert!(report.ready);
+
+        // The sync loop is stuck
+        let report = state.report(max_sync_age, now + Duration::from_secs(120));
+        assert!(!report.healthy);
+        assert!(!report.ready);
+    }
+}
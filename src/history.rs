@@ -0,0 +1,301 @@
+//! Recent messages in each room, so plugins can look back at more than the event they're handed
+//!
+//! The most recent text messages of every room are kept in memory as they arrive. Older messages,
+//! and the messages of a thread, can be fetched from the homeserver by paginating backwards
+//! through `/messages`.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use matrix_sdk::{api::r0::message::get_message_events, Client};
+use matrix_sdk_common::identifiers::{EventId, RoomId, UserId};
+use serde_json::Value as JsonValue;
+
+use crate::config::HistoryConfig;
+use crate::context::{strip_reply_fallback, thread_root};
+
+/// The largest number of pages to request from the homeserver when fetching messages
+const MAX_PAGES: usize = 10;
+
+/// A text message that was sent in a room
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryMessage {
+    pub event_id: EventId,
+    pub sender: UserId,
+    /// The text of the message, or of its latest edit, without the quote of the message it
+    /// replies to
+    pub body: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+pub struct History {
+    client: Client,
+    /// The most messages to keep for each room
+    size: usize,
+    /// The recent messages of each room, oldest first
+    rooms: Mutex<HashMap<RoomId, VecDeque<HistoryMessage>>>,
+}
+
+impl History {
+    pub fn new(client: Client, config: HistoryConfig) -> History {
+        History {
+            client,
+            size: config.size,
+            rooms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds `message` to the recent messages of `room_id`, forgetting the oldest one if there
+    /// are too many
+    pub fn record(&self, room_id: &RoomId, message: HistoryMessage) {
+        if self.size == 0 {
+            return;
+        }
+
+        let mut rooms = self.rooms.lock().unwrap();
+        let messages = rooms.entry(room_id.clone()).or_default();
+
+        // The same event can be delivered again after reconnecting
+        if messages.iter().any(|m| m.event_id == message.event_id) {
+            return;
+        }
+
+        while messages.len() >= self.size {
+            messages.pop_front();
+        }
+        messages.push_back(message);
+    }
+
+    /// Replaces the text of the message `event_id` with `body` after `sender` edited it
+    ///
+    /// Only the sender of a message can edit it, so edits by anyone else are ignored.
+    pub fn edit(&self, room_id: &RoomId, event_id: &EventId, sender: &UserId, body: &str) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let message = rooms.get_mut(room_id).and_then(|messages| {
+            messages
+                .iter_mut()
+                .find(|m| m.event_id == *event_id && m.sender == *sender)
+        });
+
+        if let Some(message) = message {
+            message.body = body.to_string();
+        }
+    }
+
+    /// Forgets the message `event_id` after it was redacted
+    pub fn redact(&self, room_id: &RoomId, event_id: &EventId) {
+        if let Some(messages) = self.rooms.lock().unwrap().get_mut(room_id) {
+            messages.retain(|m| m.event_id != *event_id);
+        }
+    }
+
+    /// Returns the message `event_id` if it's one of the recent messages in `room_id`
+    pub fn get(&self, room_id: &RoomId, event_id: &EventId) -> Option<HistoryMessage> {
+        let rooms = self.rooms.lock().unwrap();
+        let messages = rooms.get(room_id)?;
+
+        messages.iter().find(|m| m.event_id == *event_id).cloned()
+    }
+
+    /// Returns up to `limit` of the most recent messages in `room_id` that we've seen since we
+    /// started, newest first
+    pub fn recent(&self, room_id: &RoomId, limit: usize) -> Vec<HistoryMessage> {
+        match self.rooms.lock().unwrap().get(room_id) {
+            Some(messages) => messages.iter().rev().take(limit).cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    /// Fetches up to `limit` of the most recent text messages in `room_id` from the homeserver,
+    /// newest first
+    ///
+    /// Unlike [`History::recent`], this includes messages from before we started. Edits are
+    /// skipped rather than applied, and so are encrypted messages.
+    pub async fn fetch(
+        &self,
+        room_id: &RoomId,
+        limit: usize,
+    ) -> Result<Vec<HistoryMessage>, matrix_sdk::Error> {
+        self.fetch_where(room_id, limit, None, |_| true).await
+    }
+
+    /// Fetches up to `limit` of the most recent text messages in the thread `root` of `room_id`
+    /// from the homeserver, newest first, ending with the root itself
+    ///
+    /// The homeserver can't be asked for the messages of a thread, so this looks through the
+    /// recent messages of the room, and may not reach the start of a long or old thread.
+    pub async fn fetch_thread(
+        &self,
+        room_id: &RoomId,
+        root: &EventId,
+        limit: usize,
+    ) -> Result<Vec<HistoryMessage>, matrix_sdk::Error> {
+        self.fetch_where(room_id, limit, Some(root), |event| {
+            thread_root(event).as_ref() == Some(root)
+        })
+        .await
+    }
+
+    /// Fetches up to `limit` of the most recent text messages in `room_id` that `keep` accepts,
+    /// newest first, stopping early at the message `until`
+    async fn fetch_where<F>(
+        &self,
+        room_id: &RoomId,
+        limit: usize,
+        until: Option<&EventId>,
+        keep: F,
+    ) -> Result<Vec<HistoryMessage>, matrix_sdk::Error>
+    where
+        F: Fn(&JsonValue) -> bool,
+    {
+        let mut from = match self.client.sync_token().await {
+            Some(token) => token,
+            None => return Ok(vec![]),
+        };
+        let mut messages = vec![];
+
+        'pages: for _ in 0..MAX_PAGES {
+            let request = get_message_events::Request::backward(room_id, &from);
+            let response = self.client.send(request).await?;
+
+            for event in response.chunk.iter() {
+                let event = serde_json::to_value(event)?;
+                let is_until = until.map_or(false, |until| event["event_id"] == until.as_str());
+
+                if is_until || keep(&event) {
+                    if let Some(message) = parse_message(&event) {
+                        messages.push(message);
+                    }
+                }
+
+                if is_until {
+                    break 'pages;
+                }
+            }
+
+            from = match response.end {
+                Some(end) if messages.len() < limit && !response.chunk.is_empty() => end,
+                _ => break,
+            };
+        }
+
+        messages.truncate(limit);
+
+        Ok(messages)
+    }
+}
+
+/// Returns the text message in the room event `event`, or `None` if it's any other kind of event
+pub fn parse_message(event: &JsonValue) -> Option<HistoryMessage> {
+    let content = &event["content"];
+
+    if event["type"] != "m.room.message"
+        || content["msgtype"] != "m.text"
+        || content["m.relates_to"]["rel_type"] == "m.replace"
+    {
+        return None;
+    }
+
+    let event_id = EventId::try_from(event["event_id"].as_str()?).ok()?;
+    let sender = UserId::try_from(event["sender"].as_str()?).ok()?;
+    let sent_at = UNIX_EPOCH + Duration::from_millis(event["origin_server_ts"].as_u64()?);
+
+    Some(HistoryMessage {
+        event_id,
+        sender,
+        body: strip_reply_fallback(content["body"].as_str()?).to_string(),
+        sent_at: sent_at.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(n: u32) -> HistoryMessage {
+        HistoryMessage {
+            event_id: EventId::try_from(format!("${}:example.com", n).as_str()).unwrap(),
+            sender: UserId::try_from("@alice:example.com").unwrap(),
+            body: format!("message {}", n),
+            sent_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let client = Client::new(url::Url::parse("http://example.com").unwrap()).unwrap();
+        let history = History::new(client, HistoryConfig { size: 3 });
+        let room_id = RoomId::try_from("!room:example.com").unwrap();
+
+        for n in 1..=4 {
+            history.record(&room_id, message(n));
+        }
+        history.record(&room_id, message(4));
+
+        let bodies = |limit| {
+            history
+                .recent(&room_id, limit)
+                .into_iter()
+                .map(|m| m.body)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(bodies(10), ["message 4", "message 3", "message 2"]);
+        assert_eq!(bodies(1), ["message 4"]);
+
+        assert!(history.get(&room_id, &message(1).event_id).is_none());
+        assert_eq!(
+            history.get(&room_id, &message(2).event_id).unwrap().body,
+            "message 2"
+        );
+
+        let alice = UserId::try_from("@alice:example.com").unwrap();
+        let mallory = UserId::try_from("@mallory:example.com").unwrap();
+        history.edit(&room_id, &message(2).event_id, &mallory, "forged");
+        history.edit(&room_id, &message(3).event_id, &alice, "edited");
+        history.redact(&room_id, &message(4).event_id);
+        assert_eq!(bodies(10), ["edited", "message 2"]);
+
+        let other_room = RoomId::try_from("!other:example.com").unwrap();
+        assert!(history.recent(&other_room, 10).is_empty());
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let event = json!({
+            "type": "m.room.message",
+            "event_id": "$1:example.com",
+            "sender": "@alice:example.com",
+            "origin_server_ts": 1_600_000_000_000u64,
+            "content": { "msgtype": "m.text", "body": "hello" },
+        });
+        let message = parse_message(&event).unwrap();
+        assert_eq!(message.body, "hello");
+        assert_eq!(message.sent_at.timestamp(), 1_600_000_000);
+
+        let notice = json!({
+            "type": "m.room.message",
+            "event_id": "$2:example.com",
+            "sender": "@bot:example.com",
+            "origin_server_ts": 1_600_000_000_000u64,
+            "content": { "msgtype": "m.notice", "body": "hello" },
+        });
+        assert_eq!(parse_message(&notice), None);
+
+        let edit = json!({
+            "type": "m.room.message",
+            "event_id": "$3:example.com",
+            "sender": "@alice:example.com",
+            "origin_server_ts": 1_600_000_000_000u64,
+            "content": {
+                "msgtype": "m.text",
+                "body": "* hello!",
+                "m.relates_to": { "rel_type": "m.replace", "event_id": "$1:example.com" },
+            },
+        });
+        assert_eq!(parse_message(&edit), None);
+    }
+}
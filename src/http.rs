@@ -0,0 +1,782 @@
+//! A shared HTTP client for plugins that fetch things from the outside world
+//!
+//! Every request, including each hop of a redirect chain, only connects to public addresses.
+//! Host names are checked by the resolver that the connection uses, so a DNS server can't answer
+//! with a public address for the check and a private one for the connection. Proxies from the
+//! environment are never used, since a proxy would make the connections instead. Response bodies
+//! are capped in size, and successful responses are cached unless a request opts out.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::body::HttpBody;
+use hyper::client::connect::dns::Name;
+use hyper::client::HttpConnector;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, LOCATION, USER_AGENT,
+};
+use hyper::service::Service;
+use hyper::{Body, Request, StatusCode};
+use hyper_tls::HttpsConnector;
+use log::debug;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tokio::time;
+use url::{Host, Url};
+
+use crate::cache::Cache;
+use crate::config::HttpConfig;
+use crate::metrics;
+
+#[derive(thiserror::Error, Debug)]
+pub enum HttpError {
+    #[error("http client error")]
+    HyperError(hyper::Error),
+    #[error("invalid request")]
+    RequestError(#[from] hyper::http::Error),
+    #[error("request timed out")]
+    Timeout,
+    #[error("unsupported url scheme `{0}'")]
+    UnsupportedScheme(String),
+    #[error("url has no host")]
+    MissingHost,
+    #[error("refusing to connect to non-public address {0}")]
+    ForbiddenAddress(IpAddr),
+    #[error("too many redirects")]
+    TooManyRedirects,
+    #[error("invalid redirect location")]
+    InvalidRedirect,
+    #[error("unable to parse url")]
+    UrlParseError(#[from] url::ParseError),
+    #[error("unexpected http status {0}")]
+    Status(StatusCode),
+}
+
+impl From<hyper::Error> for HttpError {
+    fn from(err: hyper::Error) -> HttpError {
+        // The resolver refusing an address ends up as the cause of a connection error
+        let mut source = err.source();
+
+        while let Some(cause) = source {
+            if let Some(ForbiddenAddressError(ip)) = cause.downcast_ref() {
+                return HttpError::ForbiddenAddress(*ip);
+            }
+
+            source = cause.source();
+        }
+
+        HttpError::HyperError(err)
+    }
+}
+
+/// The error of [`PublicResolver`] when a host resolves to a non-public address
+#[derive(Debug)]
+struct ForbiddenAddressError(IpAddr);
+
+impl fmt::Display for ForbiddenAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "refusing to connect to non-public address {}", self.0)
+    }
+}
+
+impl StdError for ForbiddenAddressError {}
+
+/// Resolves host names with the system resolver, refusing names with any non-public address
+#[derive(Clone)]
+struct PublicResolver {
+    allow_private_addresses: bool,
+}
+
+type ResolveFuture =
+    Pin<Box<dyn Future<Output = Result<std::vec::IntoIter<IpAddr>, ResolveError>> + Send>>;
+type ResolveError = Box<dyn StdError + Send + Sync>;
+
+impl Service<Name> for PublicResolver {
+    type Response = std::vec::IntoIter<IpAddr>;
+    type Error = ResolveError;
+    type Future = ResolveFuture;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), ResolveError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> ResolveFuture {
+        let allow_private_addresses = self.allow_private_addresses;
+
+        Box::pin(async move {
+            let addresses: Vec<IpAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .map(|addr| addr.ip())
+                .collect();
+
+            if !allow_private_addresses {
+                if let Some(&ip) = addresses.iter().find(|&&ip| is_private_ip(ip)) {
+                    return Err(ForbiddenAddressError(ip).into());
+                }
+            }
+
+            Ok(addresses.into_iter())
+        })
+    }
+}
+
+/// An HTTP client that is shared between all plugins
+#[derive(Clone)]
+pub struct HttpClient {
+    client: hyper::Client<HttpsConnector<HttpConnector<PublicResolver>>>,
+    config: Arc<HttpConfig>,
+    cache: Cache,
+}
+
+/// A GET request that is about to be sent
+pub struct RequestBuilder<'a> {
+    http: &'a HttpClient,
+    url: Url,
+    max_body_size: usize,
+    timeout: Option<Duration>,
+    use_cache: bool,
+}
+
+/// A response with a fully read, size-limited body
+#[derive(Debug, Clone)]
+pub struct Response {
+    /// The URL of the response, after following redirects
+    pub url: Url,
+    /// The status code of the response
+    pub status: StatusCode,
+    /// The response headers
+    pub headers: HeaderMap,
+    /// The response body, truncated to the maximum body size
+    pub body: Vec<u8>,
+}
+
+/// The cached form of a [`Response`]
+#[derive(Serialize, Deserialize)]
+struct CachedResponse {
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// The body as base64, which is a lot smaller than a JSON array of bytes
+    #[serde(with = "base64_body")]
+    body: Vec<u8>,
+    /// The maximum body size of the request that produced the response
+    max_body_size: usize,
+}
+
+mod base64_body {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(body: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(body))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let body = String::deserialize(deserializer)?;
+
+        base64::decode(&body).map_err(de::Error::custom)
+    }
+}
+
+/// Returns whether `ip` belongs to a loopback, link-local, private or otherwise non-public range
+pub fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+                // Benchmarking, 198.18.0.0/15
+                || (octets[0] == 198 && (octets[1] & 0xfe) == 18)
+                // Reserved, 240.0.0.0/4, which includes the broadcast address
+                || octets[0] >= 240
+                // "This network", 0.0.0.0/8
+                || octets[0] == 0
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+
+            if let Some(ip) = ip.to_ipv4() {
+                // IPv4-mapped and IPv4-compatible addresses
+                if segments[..5].iter().all(|&s| s == 0) {
+                    return is_private_ip(IpAddr::V4(ip));
+                }
+            }
+
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local, fc00::/7
+                || (segments[0] & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10
+                || (segments[0] & 0xffc0) == 0xfe80
+                // 6to4, 2002::/16, which can embed any IPv4 address
+                || segments[0] == 0x2002
+                // NAT64, 64:ff9b::/96, which can embed any IPv4 address
+                || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+        }
+    }
+}
+
+impl HttpClient {
+    /// Constructs a new HTTP client with the given `config`, caching responses in `cache`
+    pub fn new(config: HttpConfig, cache: Cache) -> Result<HttpClient, HttpError> {
+        let mut connector = HttpConnector::new_with_resolver(PublicResolver {
+            allow_private_addresses: config.allow_private_addresses,
+        });
+
+        connector.enforce_http(false);
+        connector.set_connect_timeout(Some(Duration::from_secs(config.connect_timeout)));
+
+        // Redirects aren't followed by hyper, so we follow them manually and check every hop
+        let client = hyper::Client::builder().build(HttpsConnector::new_with_connector(connector));
+
+        Ok(HttpClient {
+            client,
+            config: Arc::new(config),
+            cache,
+        })
+    }
+
+    /// Returns a new GET request for `url`
+    pub fn get(&self, url: Url) -> RequestBuilder<'_> {
+        RequestBuilder {
+            http: self,
+            url,
+            max_body_size: self.config.max_body_size,
+            timeout: None,
+            use_cache: true,
+        }
+    }
+
+    /// Returns an error if `url` isn't something we're willing to fetch
+    ///
+    /// Host names are checked when they're resolved, so only addresses in the URL itself are
+    /// checked here.
+    fn check_url(&self, url: &Url) -> Result<(), HttpError> {
+        match url.scheme() {
+            "http" | "https" => {}
+            scheme => return Err(HttpError::UnsupportedScheme(scheme.to_string())),
+        }
+
+        let ip = match url.host() {
+            Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+            Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+            Some(Host::Domain(_)) => return Ok(()),
+            None => return Err(HttpError::MissingHost),
+        };
+
+        if !self.config.allow_private_addresses && is_private_ip(ip) {
+            return Err(HttpError::ForbiddenAddress(ip));
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> RequestBuilder<'a> {
+    /// Sets the maximum number of bytes to read from the response body
+    pub fn max_body_size(mut self, max_body_size: usize) -> RequestBuilder<'a> {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Sets the maximum time the whole redirect chain, including reading the body, may take
+    pub fn timeout(mut self, timeout: Duration) -> RequestBuilder<'a> {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Skips the cache for this request, both for lookup and for storing the response
+    pub fn no_cache(mut self) -> RequestBuilder<'a> {
+        self.use_cache = false;
+        self
+    }
+
+    /// Sends the request, following redirects, and reads the response
+    ///
+    /// Successful responses are served from and stored in the cache, unless disabled
+    pub async fn send(self) -> Result<Response, HttpError> {
+        // The URL has already been normalized by the parser
+        let key = self.url.to_string();
+
+        if self.use_cache {
+            if let Some(cached) = self.http.cache.get::<CachedResponse>(&key).await {
+                if let Some(response) = cached.into_response(self.max_body_size) {
+                    debug!("Serving {} from cache", key);
+                    metrics::HTTP_REQUESTS.with_label_values(&["cached"]).inc();
+
+                    return Ok(response);
+                }
+            }
+        }
+
+        let use_cache = self.use_cache;
+        let max_body_size = self.max_body_size;
+        let cache = self.http.cache.clone();
+        let response = self.fetch().await;
+        let result = if response.is_ok() { "ok" } else { "error" };
+
+        metrics::HTTP_REQUESTS.with_label_values(&[result]).inc();
+
+        let response = response?;
+
+        if use_cache && response.status.is_success() {
+            let cached = CachedResponse::new(&response, max_body_size);
+
+            cache.insert(&key, &cached).await;
+        }
+
+        Ok(response)
+    }
+
+    /// Fetches the response, with one timeout for following every redirect and reading the body
+    async fn fetch(self) -> Result<Response, HttpError> {
+        let timeout = self
+            .timeout
+            .unwrap_or_else(|| Duration::from_secs(self.http.config.timeout));
+
+        time::timeout(timeout, self.follow_redirects())
+            .await
+            .map_err(|_| HttpError::Timeout)?
+    }
+
+    async fn follow_redirects(self) -> Result<Response, HttpError> {
+        let mut url = self.url;
+        let mut redirects = 0;
+
+        loop {
+            self.http.check_url(&url)?;
+
+            let request = Request::get(url.as_str())
+                .header(USER_AGENT, self.http.config.user_agent.as_str())
+                .body(Body::empty())?;
+            let response = self.http.client.request(request).await?;
+
+            if response.status().is_redirection() {
+                if let Some(location) = response.headers().get(LOCATION) {
+                    if redirects >= self.http.config.max_redirects {
+                        return Err(HttpError::TooManyRedirects);
+                    }
+
+                    let location = location.to_str().map_err(|_| HttpError::InvalidRedirect)?;
+
+                    debug!("Following redirect from {} to {}", url, location);
+
+                    url = url.join(location)?;
+                    redirects += 1;
+
+                    continue;
+                }
+            }
+
+            return Response::read(url, response, self.max_body_size).await;
+        }
+    }
+}
+
+impl CachedResponse {
+    fn new(response: &Response, max_body_size: usize) -> CachedResponse {
+        let headers = response
+            .headers
+            .iter()
+            .filter_map(|(name, value)| {
+                value
+                    .to_str()
+                    .ok()
+                    .map(|value| (name.as_str().to_string(), value.to_string()))
+            })
+            .collect();
+
+        CachedResponse {
+            url: response.url.to_string(),
+            status: response.status.as_u16(),
+            headers,
+            body: response.body.clone(),
+            max_body_size,
+        }
+    }
+
+    /// Converts the cached response back into a response for a request with the given maximum
+    /// body size
+    ///
+    /// Returns `None` if the cached body might have been cut shorter than what is requested, or
+    /// if the entry is invalid.
+    fn into_response(mut self, max_body_size: usize) -> Option<Response> {
+        if self.max_body_size < max_body_size && self.body.len() >= self.max_body_size {
+            return None;
+        }
+
+        self.body.truncate(max_body_size);
+
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            let value = HeaderValue::from_str(&value).ok()?;
+
+            headers.append(name, value);
+        }
+
+        Some(Response {
+            url: Url::parse(&self.url).ok()?,
+            status: StatusCode::from_u16(self.status).ok()?,
+            headers,
+            body: self.body,
+        })
+    }
+}
+
+impl Response {
+    /// Reads at most `max_body_size` bytes of the body of `response`
+    async fn read(
+        url: Url,
+        response: hyper::Response<Body>,
+        max_body_size: usize,
+    ) -> Result<Response, HttpError> {
+        let (parts, mut stream) = response.into_parts();
+        let mut body = Vec::new();
+
+        while let Some(chunk) = stream.data().await {
+            let chunk = chunk?;
+            let remaining = max_body_size - body.len();
+
+            if chunk.len() >= remaining {
+                body.extend_from_slice(&chunk[..remaining]);
+                break;
+            }
+
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(Response {
+            url,
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        })
+    }
+
+    /// Returns an error if the status code isn't a success
+    pub fn error_for_status(self) -> Result<Response, HttpError> {
+        if self.status.is_success() {
+            Ok(self)
+        } else {
+            Err(HttpError::Status(self.status))
+        }
+    }
+
+    /// Returns the body as text, replacing invalid UTF-8 sequences
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    /// Returns the lowercased media type of the response, without any parameters
+    pub fn content_type(&self) -> Option<String> {
+        self.headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_lowercase())
+    }
+
+    /// Returns the length of the full body as reported by the server
+    pub fn content_length(&self) -> Option<u64> {
+        self.headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CacheConfig;
+    use crate::storage::{MemoryBackend, Storage};
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Starts a tiny HTTP server on a random local port
+    ///
+    /// `/redirect/<n>` redirects `n` times before ending up at `/`, `/slow/<n>` does the same but
+    /// waits 200 milliseconds before each redirect, `/big` returns a large body, `/count` returns
+    /// the number of requests served so far and everything else echoes back the user agent.
+    async fn serve() -> SocketAddr {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let count = AtomicUsize::new(0);
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let count = count.fetch_add(1, Ordering::SeqCst) + 1;
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let n = socket.read(&mut buf).await.unwrap();
+
+                    if n == 0 {
+                        break;
+                    }
+
+                    request.extend_from_slice(&buf[..n]);
+                }
+
+                let request = String::from_utf8_lossy(&request).into_owned();
+                let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+                let user_agent = request
+                    .lines()
+                    .find(|line| line.to_lowercase().starts_with("user-agent:"))
+                    .map(|line| line[11..].trim().to_string())
+                    .unwrap_or_default();
+
+                let redirect = path
+                    .strip_prefix("/redirect/")
+                    .map(|n| ("redirect", n))
+                    .or_else(|| path.strip_prefix("/slow/").map(|n| ("slow", n)));
+
+                let response = if let Some((kind, n)) = redirect {
+                    let n: usize = n.parse().unwrap();
+                    let location = if n > 1 {
+                        format!("/{}/{}", kind, n - 1)
+                    } else {
+                        "/".to_string()
+                    };
+
+                    if kind == "slow" {
+                        time::delay_for(Duration::from_millis(200)).await;
+                    }
+
+                    format!(
+                        "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        location
+                    )
+                } else if path == "/count" {
+                    let body = count.to_string();
+
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                } else if path == "/big" {
+                    let body = "a".repeat(64 * 1024);
+
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                } else {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        user_agent.len(),
+                        user_agent
+                    )
+                };
+
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        addr
+    }
+
+    fn cache() -> Cache {
+        let storage = Storage::new(Arc::new(MemoryBackend::new()), "http");
+
+        Cache::new(&CacheConfig::default(), storage)
+    }
+
+    fn local_client(config: HttpConfig) -> HttpClient {
+        let config = HttpConfig {
+            allow_private_addresses: true,
+            ..config
+        };
+
+        HttpClient::new(config, cache()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_blocks_loopback() {
+        let addr = serve().await;
+        let http = HttpClient::new(HttpConfig::default(), cache()).unwrap();
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+
+        match http.get(url).send().await {
+            Err(HttpError::ForbiddenAddress(ip)) => assert!(ip.is_loopback()),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocks_resolved_loopback() {
+        let addr = serve().await;
+        let http = HttpClient::new(HttpConfig::default(), cache()).unwrap();
+        let url = Url::parse(&format!("http://localhost:{}/", addr.port())).unwrap();
+
+        match http.get(url).send().await {
+            Err(HttpError::ForbiddenAddress(ip)) => assert!(ip.is_loopback()),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocks_unsupported_scheme() {
+        let http = HttpClient::new(HttpConfig::default(), cache()).unwrap();
+        let url = Url::parse("file:///etc/passwd").unwrap();
+
+        assert!(matches!(
+            http.get(url).send().await,
+            Err(HttpError::UnsupportedScheme(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_user_agent() {
+        let addr = serve().await;
+        let http = local_client(HttpConfig {
+            user_agent: "meta-matrix-test".to_string(),
+            ..HttpConfig::default()
+        });
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let response = http.get(url).send().await.unwrap();
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.text(), "meta-matrix-test");
+        assert_eq!(response.content_type(), Some("text/plain".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_max_body_size() {
+        let addr = serve().await;
+        let http = local_client(HttpConfig {
+            max_body_size: 1000,
+            ..HttpConfig::default()
+        });
+        let url = Url::parse(&format!("http://{}/big", addr)).unwrap();
+
+        let response = http.get(url.clone()).send().await.unwrap();
+        assert_eq!(response.body.len(), 1000);
+        assert_eq!(response.content_length(), Some(64 * 1024));
+
+        let response = http.get(url).max_body_size(10).send().await.unwrap();
+        assert_eq!(response.body.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_redirects() {
+        let addr = serve().await;
+        let http = local_client(HttpConfig {
+            max_redirects: 2,
+            ..HttpConfig::default()
+        });
+
+        let url = Url::parse(&format!("http://{}/redirect/2", addr)).unwrap();
+        let response = http.get(url).send().await.unwrap();
+        assert_eq!(response.url.path(), "/");
+
+        let url = Url::parse(&format!("http://{}/redirect/3", addr)).unwrap();
+        assert!(matches!(
+            http.get(url).send().await,
+            Err(HttpError::TooManyRedirects)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_covers_redirects() {
+        let addr = serve().await;
+        let http = local_client(HttpConfig::default());
+        let timeout = Duration::from_millis(500);
+
+        // Every hop is well within the timeout, but the whole chain isn't
+        let url = Url::parse(&format!("http://{}/slow/4", addr)).unwrap();
+        assert!(matches!(
+            http.get(url).timeout(timeout).send().await,
+            Err(HttpError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let addr = serve().await;
+        let http = local_client(HttpConfig::default());
+        let url = Url::parse(&format!("http://{}/count", addr)).unwrap();
+
+        let first = http.get(url.clone()).send().await.unwrap();
+        let cached = http.get(url.clone()).send().await.unwrap();
+        let uncached = http.get(url).no_cache().send().await.unwrap();
+
+        assert_eq!(first.text(), "1");
+        assert_eq!(cached.text(), "1");
+        assert_eq!(uncached.text(), "2");
+    }
+
+    #[test]
+    fn test_cached_response_body() {
+        let response = Response {
+            url: Url::parse("http://example.com/").unwrap(),
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: vec![0, 159, 146, 150, b'a'],
+        };
+        let cached = serde_json::to_value(CachedResponse::new(&response, 1000)).unwrap();
+
+        assert_eq!(cached["body"], "AJ+SlmE=");
+
+        let cached: CachedResponse = serde_json::from_value(cached).unwrap();
+        assert_eq!(cached.into_response(1000).unwrap().body, response.body);
+    }
+
+    #[test]
+    fn test_is_private_ip() {
+        for ip in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+            "2002:7f00:1::",
+            "64:ff9b::7f00:1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "::ffff:100.64.0.1",
+        ] {
+            assert!(is_private_ip(ip.parse().unwrap()), "{} is private", ip);
+        }
+
+        for ip in &[
+            "93.184.216.34",
+            "100.128.0.1",
+            "198.20.0.1",
+            "2606:2800:220:1::248",
+            "::ffff:93.184.216.34",
+        ] {
+            assert!(!is_private_ip(ip.parse().unwrap()), "{} is public", ip);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Sets up logging, and allows changing the log level of plugins at runtime
+//!
+//! Log lines that are written while a plugin handles an event carry the room id, sender, event
+//! id and plugin name as fields, either human-readable or as JSON lines, depending on the config.
+//!
+//! The log level of a plugin applies to everything logged while it handles an event, by matching
+//! on the `plugin` field of the event span, so it also covers the crates that the plugin calls.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use tracing_subscriber::{
+    filter::{EnvFilter, LevelFilter},
+    fmt,
+    prelude::*,
+    reload, Registry,
+};
+
+use crate::config::{LogFormat, LoggingConfig};
+
+#[derive(thiserror::Error, Debug)]
+pub enum LoggingError {
+    #[error("invalid log level `{0}'")]
+    InvalidLevel(String),
+    #[error("invalid plugin name `{0}'")]
+    InvalidPlugin(String),
+    #[error("logging is already initialized")]
+    AlreadyInitialized,
+    #[error("could not reload the log filter")]
+    ReloadError(#[from] reload::Error),
+}
+
+/// The current log levels, and a handle to update the active filter with
+struct Levels {
+    /// The default log level
+    default: LevelFilter,
+    /// The log levels of plugins, by type name
+    plugins: BTreeMap<String, LevelFilter>,
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+lazy_static! {
+    static ref LEVELS: Mutex<Option<Levels>> = Mutex::new(None);
+}
+
+/// Parses a log level such as `debug` or `off`
+pub fn parse_level(level: &str) -> Result<LevelFilter, LoggingError> {
+    level
+        .parse()
+        .map_err(|_| LoggingError::InvalidLevel(level.to_string()))
+}
+
+/// Returns the filter directives for the given levels
+fn filter_directives(default: LevelFilter, plugins: &BTreeMap<String, LevelFilter>) -> String {
+    let mut directives = vec![default.to_string()];
+
+    for (plugin, level) in plugins {
+        directives.push(format!("[event{{plugin={}}}]={}", plugin, level));
+    }
+
+    directives.join(",")
+}
+
+/// Returns the log filter for the given levels
+fn build_filter(default: LevelFilter, plugins: &BTreeMap<String, LevelFilter>) -> EnvFilter {
+    EnvFilter::new(filter_directives(default, plugins))
+}
+
+/// Returns whether `plugin` looks like the type name of a plugin
+///
+/// This keeps arbitrary filter directives from being injected through plugin names
+fn is_plugin_name(plugin: &str) -> bool {
+    !plugin.is_empty()
+        && plugin
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Installs the global logger, which also receives everything logged through the `log` crate
+pub fn init(config: &LoggingConfig) -> Result<(), LoggingError> {
+    let default = parse_level(&config.level)?;
+    let mut plugins = BTreeMap::new();
+
+    for (plugin, level) in config.plugins.iter() {
+        if !is_plugin_name(plugin) {
+            return Err(LoggingError::InvalidPlugin(plugin.clone()));
+        }
+
+        plugins.insert(plugin.clone(), parse_level(level)?);
+    }
+
+    let (filter, handle) = reload::Layer::new(build_filter(default, &plugins));
+    let registry = tracing_subscriber::registry().with(filter);
+    let result = match config.format {
+        LogFormat::Pretty => registry.with(fmt::layer()).try_init(),
+        LogFormat::Json => registry
+            .with(fmt::layer().json().with_current_span(true))
+            .try_init(),
+    };
+
+    result.map_err(|_| LoggingError::AlreadyInitialized)?;
+
+    *LEVELS.lock().unwrap() = Some(Levels {
+        default,
+        plugins,
+        handle,
+    });
+
+    Ok(())
+}
+
+/// Sets the log level of the plugin with the type name `plugin`, or resets it to the default
+/// level if `level` is `None`
+pub fn set_plugin_level(plugin: &str, level: Option<LevelFilter>) -> Result<(), LoggingError> {
+    if !is_plugin_name(plugin) {
+        return Err(LoggingError::InvalidPlugin(plugin.to_string()));
+    }
+
+    let mut levels = LEVELS.lock().unwrap();
+    let levels = match levels.as_mut() {
+        Some(levels) => levels,
+        // Logging isn't set up, so there's nothing to change
+        None => return Ok(()),
+    };
+
+    match level {
+        Some(level) => levels.plugins.insert(plugin.to_string(), level),
+        None => levels.plugins.remove(plugin),
+    };
+
+    levels
+        .handle
+        .reload(build_filter(levels.default, &levels.plugins))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_plugin_name() {
+        assert!(is_plugin_name("UrlPreviewPlugin"));
+        assert!(!is_plugin_name(""));
+        assert!(!is_plugin_name("PollPlugin}]=trace,hyper"));
+    }
+
+    #[test]
+    fn test_filter_directives() {
+        let mut plugins = BTreeMap::new();
+        plugins.insert("PollPlugin".to_string(), LevelFilter::DEBUG);
+        plugins.insert("KarmaPlugin".to_string(), LevelFilter::OFF);
+
+        assert_eq!(
+            filter_directives(LevelFilter::WARN, &plugins),
+            "warn,[event{plugin=KarmaPlugin}]=off,[event{plugin=PollPlugin}]=debug"
+        );
+    }
+}
@@ -1,13 +1,28 @@
 use std::env;
+use std::sync::Arc;
 
 use anyhow::Context;
 use log::debug;
 
+mod backoff;
+mod cache;
 mod client;
 mod config;
+mod context;
+mod dispatch;
+mod duration;
 mod error;
+mod health;
+mod history;
+mod http;
+mod logging;
+mod membership;
+mod metrics;
 mod plugin;
 mod plugins;
+mod reaction;
+mod storage;
+mod sync_state;
 
 use client::MatrixClient;
 pub use config::Config;
@@ -26,24 +41,52 @@ fn config_file_path() -> String {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    pretty_env_logger::init();
-
     // Load the config
     let config_path = config_file_path();
     let config = config::load(&config_path)
         .with_context(|| format!("failed to load config file `{}'", config_path))?;
 
+    logging::init(&config.logging).context("failed to set up logging")?;
+
+    if let Some(addr) = config.metrics.listen {
+        let server = metrics::serve(addr).context("failed to start metrics listener")?;
+
+        tokio::spawn(server);
+    }
+
+    if let Some(server) =
+        health::serve(&config.health).context("failed to start health check listener")?
+    {
+        tokio::spawn(server);
+    }
+
     debug!(
         "Logging in as {} on homeserver {}",
         &config.matrix.username, &config.matrix.homeserver
     );
 
-    let mut client = MatrixClient::with_config(config)?;
+    std::fs::create_dir_all(&config.data_dir).with_context(|| {
+        format!(
+            "failed to create data directory `{}'",
+            config.data_dir.display()
+        )
+    })?;
+
+    let storage_path = config.data_dir.join("storage.sqlite3");
+    let storage = storage::SqliteBackend::open(&storage_path)
+        .await
+        .with_context(|| format!("failed to open storage `{}'", storage_path.display()))?;
+
+    let mut client = MatrixClient::with_config(config, Arc::new(storage))?;
 
     client.init_plugins().await?;
 
     client.login().await?;
-    client.poll().await?;
+    client.start_plugins().await?;
+    client
+        .poll()
+        .await
+        .context("lost the connection to the homeserver")?;
 
     Ok(())
 }
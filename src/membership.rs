@@ -0,0 +1,165 @@
+//! Changes in room membership, and deciding which invites to accept
+
+use matrix_sdk::events::room::member::{MemberEventContent, MembershipState};
+use matrix_sdk_common::identifiers::UserId;
+
+use crate::config::{InviteConfig, InvitePolicy};
+
+/// How a user's membership of a room changed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MembershipChange {
+    /// The user joined the room
+    Joined,
+    /// The user left the room, or was kicked
+    Left,
+    /// The user was invited to the room
+    Invited,
+    /// The user was banned from the room
+    Banned,
+}
+
+/// A user whose membership of a room changed
+#[derive(Clone, Debug)]
+pub struct Member {
+    pub user_id: UserId,
+    /// The display name of the user in the room, if they have one
+    pub display_name: Option<String>,
+    pub change: MembershipChange,
+}
+
+impl Member {
+    /// Returns the display name of the user, or their user id if they don't have one
+    pub fn name(&self) -> &str {
+        self.display_name
+            .as_deref()
+            .unwrap_or_else(|| self.user_id.as_str())
+    }
+}
+
+/// Returns how the membership changed between `prev_content` and `content`
+///
+/// Returns `None` if the membership didn't change, e.g. when a member changes their display name
+pub fn membership_change(
+    content: &MemberEventContent,
+    prev_content: Option<&MemberEventContent>,
+) -> Option<MembershipChange> {
+    let previous = prev_content.map(|prev_content| &prev_content.membership);
+
+    if previous == Some(&content.membership) {
+        return None;
+    }
+
+    match content.membership {
+        MembershipState::Join => Some(MembershipChange::Joined),
+        MembershipState::Invite => Some(MembershipChange::Invited),
+        MembershipState::Ban => Some(MembershipChange::Banned),
+        MembershipState::Leave => match previous {
+            Some(MembershipState::Join) => Some(MembershipChange::Left),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// What to do with an invite to a room
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InviteResponse {
+    Accept,
+    Reject,
+    /// Leave the invite pending, so someone can still deal with it by hand
+    Ignore,
+}
+
+/// Returns what to do with an invite from `inviter`
+pub fn respond_to_invite(config: &InviteConfig, inviter: &UserId) -> InviteResponse {
+    let accept = match config.policy {
+        InvitePolicy::Ignore => return InviteResponse::Ignore,
+        InvitePolicy::Nobody => false,
+        InvitePolicy::Anyone => true,
+        InvitePolicy::Allowlist => {
+            config
+                .allowed_users
+                .iter()
+                .any(|user| user == inviter.as_str())
+                || config
+                    .allowed_homeservers
+                    .iter()
+                    .any(|server| server.eq_ignore_ascii_case(inviter.server_name().as_str()))
+        }
+    };
+
+    if accept {
+        InviteResponse::Accept
+    } else {
+        InviteResponse::Reject
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn content(membership: MembershipState) -> MemberEventContent {
+        serde_json::from_value(serde_json::json!({ "membership": membership })).unwrap()
+    }
+
+    fn user(id: &str) -> UserId {
+        UserId::try_from(id).unwrap()
+    }
+
+    #[test]
+    fn test_membership_change() {
+        let join = content(MembershipState::Join);
+        let leave = content(MembershipState::Leave);
+        let invite = content(MembershipState::Invite);
+
+        assert_eq!(
+            membership_change(&join, None),
+            Some(MembershipChange::Joined)
+        );
+        assert_eq!(
+            membership_change(&join, Some(&invite)),
+            Some(MembershipChange::Joined)
+        );
+        assert_eq!(membership_change(&join, Some(&join)), None);
+        assert_eq!(
+            membership_change(&leave, Some(&join)),
+            Some(MembershipChange::Left)
+        );
+        // Rejecting an invite isn't leaving
+        assert_eq!(membership_change(&leave, Some(&invite)), None);
+    }
+
+    #[test]
+    fn test_respond_to_invite() {
+        let respond = |config: &InviteConfig, inviter| respond_to_invite(config, &user(inviter));
+        let mut config = InviteConfig::default();
+        assert_eq!(
+            respond(&config, "@alice:example.com"),
+            InviteResponse::Ignore
+        );
+
+        config.policy = InvitePolicy::Nobody;
+        assert_eq!(
+            respond(&config, "@alice:example.com"),
+            InviteResponse::Reject
+        );
+
+        config.policy = InvitePolicy::Anyone;
+        assert_eq!(
+            respond(&config, "@alice:example.com"),
+            InviteResponse::Accept
+        );
+
+        config.policy = InvitePolicy::Allowlist;
+        config.allowed_users = vec!["@alice:example.com".to_string()];
+        config.allowed_homeservers = vec!["example.org".to_string()];
+        assert_eq!(
+            respond(&config, "@alice:example.com"),
+            InviteResponse::Accept
+        );
+        assert_eq!(respond(&config, "@bob:example.org"), InviteResponse::Accept);
+        assert_eq!(respond(&config, "@bob:example.com"), InviteResponse::Reject);
+    }
+}
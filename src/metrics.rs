@@ -0,0 +1,161 @@
+//! Prometheus metrics, and an optional HTTP listener that serves them
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use log::{error, info};
+use matrix_sdk_common::{api::error::ErrorKind, FromHttpResponseError, ServerError};
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref SYNC_DURATION: Histogram = register_histogram!(
+        "meta_matrix_sync_duration_seconds",
+        "Time each sync request to the homeserver took"
+    )
+    .unwrap();
+    pub static ref SYNC_ERRORS: IntCounter = register_int_counter!(
+        "meta_matrix_sync_errors_total",
+        "Number of syncs with the homeserver that failed"
+    )
+    .unwrap();
+    pub static ref SYNC_RECONNECTS: IntCounter = register_int_counter!(
+        "meta_matrix_sync_reconnects_total",
+        "Number of times syncing with the homeserver succeeded again after failing"
+    )
+    .unwrap();
+    pub static ref PLUGIN_EVENTS: IntCounterVec = register_int_counter_vec!(
+        "meta_matrix_plugin_events_total",
+        "Number of events dispatched to plugins",
+        &["plugin", "event"]
+    )
+    .unwrap();
+    pub static ref PLUGIN_HANDLER_DURATION: HistogramVec = register_histogram_vec!(
+        "meta_matrix_plugin_handler_duration_seconds",
+        "Time plugins spent handling events",
+        &["plugin", "event"]
+    )
+    .unwrap();
+    pub static ref PLUGIN_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "meta_matrix_plugin_failures_total",
+        "Number of times plugins failed, by what they were doing",
+        &["plugin", "stage"]
+    )
+    .unwrap();
+    pub static ref MESSAGES_SENT: IntCounterVec = register_int_counter_vec!(
+        "meta_matrix_messages_sent_total",
+        "Number of events sent to rooms",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "meta_matrix_http_requests_total",
+        "Number of outbound HTTP requests made by plugins",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref RATE_LIMITED: IntCounter = register_int_counter!(
+        "meta_matrix_rate_limited_total",
+        "Number of events that were dropped because the homeserver rate limited us"
+    )
+    .unwrap();
+}
+
+/// Returns whether the homeserver refused a request because we're sending too fast
+fn is_rate_limited(err: &matrix_sdk::Error) -> bool {
+    match err {
+        matrix_sdk::Error::RumaResponse(FromHttpResponseError::Http(ServerError::Known(err))) => {
+            matches!(err.kind, ErrorKind::LimitExceeded { .. })
+        }
+        _ => false,
+    }
+}
+
+/// Records the result of sending an event to a room
+pub fn observe_send<T>(result: &Result<T, matrix_sdk::Error>) {
+    match result {
+        Ok(_) => MESSAGES_SENT.with_label_values(&["ok"]).inc(),
+        Err(err) => {
+            MESSAGES_SENT.with_label_values(&["error"]).inc();
+
+            if is_rate_limited(err) {
+                RATE_LIMITED.inc();
+            }
+        }
+    }
+}
+
+async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let encoder = TextEncoder::new();
+            let mut body = vec![];
+
+            match encoder.encode(&prometheus::gather(), &mut body) {
+                Ok(()) => Response::builder()
+                    .header(CONTENT_TYPE, encoder.format_type())
+                    .body(Body::from(body)),
+                Err(err) => {
+                    error!("Failed to encode metrics: {}", err);
+
+                    Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::empty())
+                }
+            }
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+
+    Ok(response.expect("responses are always valid"))
+}
+
+/// Binds to `addr` and returns a future that serves metrics on it forever
+pub fn serve(addr: SocketAddr) -> Result<impl Future<Output = ()>, hyper::Error> {
+    let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    Ok(async move {
+        if let Err(err) = server.await {
+            error!("Metrics listener failed: {}", err);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serve_metrics() {
+        PLUGIN_EVENTS
+            .with_label_values(&["TestPlugin", "text"])
+            .inc();
+
+        let response = handle(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(
+            body.contains(r#"meta_matrix_plugin_events_total{event="text",plugin="TestPlugin"}"#)
+        );
+
+        let response = handle(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
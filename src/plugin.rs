@@ -1,20 +1,59 @@
+use std::sync::Arc;
+
+use crate::context::MessageContext;
+use crate::health::{self, PluginState};
+use crate::history::History;
+use crate::http::HttpClient;
+use crate::membership::Member;
+use crate::metrics;
+use crate::reaction::Reaction;
+use crate::storage::{Storage, StorageBackend};
+use crate::{Config, Error};
 use async_trait::async_trait;
 use log::{debug, error};
 use matrix_sdk::{
-    events::room::message::{
-        AudioMessageEventContent, EmoteMessageEventContent, FileMessageEventContent,
-        ImageMessageEventContent, LocationMessageEventContent, NoticeMessageEventContent,
-        ServerNoticeMessageEventContent, TextMessageEventContent, VideoMessageEventContent,
+    events::{
+        custom::CustomEventContent,
+        room::message::{
+            AudioMessageEventContent, EmoteMessageEventContent, FileMessageEventContent,
+            ImageMessageEventContent, LocationMessageEventContent, NoticeMessageEventContent,
+            ServerNoticeMessageEventContent, TextMessageEventContent, VideoMessageEventContent,
+        },
+        AnyToDeviceEvent,
     },
     Client,
 };
-use matrix_sdk_common::identifiers::{RoomId, UserId};
-
-use crate::Error;
 
 pub struct PluginRegistry {
     client: Client,
-    plugins: Vec<Box<dyn Plugin>>,
+    config: Arc<Config>,
+    storage_backend: Arc<dyn StorageBackend>,
+    http: HttpClient,
+    history: Arc<History>,
+    plugins: Vec<Arc<dyn Plugin>>,
+}
+
+/// The context that is handed to a plugin when it's constructed
+#[derive(Clone)]
+pub struct PluginContext {
+    /// The Matrix client
+    pub client: Client,
+    /// The parsed config file
+    pub config: Arc<Config>,
+    /// The plugins own persistent storage
+    pub storage: Storage,
+    /// The shared client for outbound HTTP requests
+    pub http: HttpClient,
+    /// The recent messages in each room
+    pub history: Arc<History>,
+}
+
+/// Returns the type name of the plugin `P` without its module path
+fn plugin_name<P: Plugin>() -> &'static str {
+    std::any::type_name::<P>()
+        .split("::")
+        .last()
+        .expect("could not extract module name")
 }
 
 #[async_trait]
@@ -32,15 +71,33 @@ pub trait Plugin: Send + Sync {
             .expect("could not extract module name")
     }
 
-    fn new(client: Client) -> Result<Self, Error>
+    fn new(ctx: PluginContext) -> Result<Self, Error>
     where
         Self: Sized;
 
+    /// Returns whether the plugin must handle the events of a room one at a time, in the order
+    /// they were received
+    ///
+    /// Otherwise, events may be handled concurrently, and finish in any order.
+    fn ordered(&self) -> bool {
+        false
+    }
+
+    /// Returns whether to show us as typing while the plugin takes long to handle a text
+    /// message, which suits plugins that answer commands
+    fn shows_typing(&self) -> bool {
+        true
+    }
+
+    /// Called once after the client has logged in, before any events are dispatched
+    async fn start(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
     /// Called when a audio message is received in a room
     async fn on_room_audio_message(
         &self,
-        _user: &UserId,
-        _room: &RoomId,
+        _ctx: &MessageContext,
         _message: &AudioMessageEventContent,
     ) {
     }
@@ -48,8 +105,7 @@ pub trait Plugin: Send + Sync {
     /// Called when a emote message is received in a room
     async fn on_room_emote_message(
         &self,
-        _user: &UserId,
-        _room: &RoomId,
+        _ctx: &MessageContext,
         _message: &EmoteMessageEventContent,
     ) {
     }
@@ -57,8 +113,7 @@ pub trait Plugin: Send + Sync {
     /// Called when a file message is received in a room
     async fn on_room_file_message(
         &self,
-        _user: &UserId,
-        _room: &RoomId,
+        _ctx: &MessageContext,
         _message: &FileMessageEventContent,
     ) {
     }
@@ -66,8 +121,7 @@ pub trait Plugin: Send + Sync {
     /// Called when a image message is received in a room
     async fn on_room_image_message(
         &self,
-        _user: &UserId,
-        _room: &RoomId,
+        _ctx: &MessageContext,
         _message: &ImageMessageEventContent,
     ) {
     }
@@ -75,8 +129,7 @@ pub trait Plugin: Send + Sync {
     /// Called when a location message is received in a room
     async fn on_room_location_message(
         &self,
-        _user: &UserId,
-        _room: &RoomId,
+        _ctx: &MessageContext,
         _message: &LocationMessageEventContent,
     ) {
     }
@@ -84,8 +137,7 @@ pub trait Plugin: Send + Sync {
     /// Called when a notice message is received in a room
     async fn on_room_notice_message(
         &self,
-        _user: &UserId,
-        _room: &RoomId,
+        _ctx: &MessageContext,
         _message: &NoticeMessageEventContent,
     ) {
     }
@@ -93,17 +145,18 @@ pub trait Plugin: Send + Sync {
     /// Called when a server notice message is received in a room
     async fn on_room_server_notice_message(
         &self,
-        _user: &UserId,
-        _room: &RoomId,
+        _ctx: &MessageContext,
         _message: &ServerNoticeMessageEventContent,
     ) {
     }
 
     /// Called when a text message is received in a room
+    ///
+    /// If the message is an edit, `message` is the new content and `ctx` says which message
+    /// was edited
     async fn on_room_text_message(
         &self,
-        _user: &UserId,
-        _room: &RoomId,
+        _ctx: &MessageContext,
         _message: &TextMessageEventContent,
     ) {
     }
@@ -111,18 +164,53 @@ pub trait Plugin: Send + Sync {
     /// Called when a video message is received in a room
     async fn on_room_video_message(
         &self,
-        _user: &UserId,
-        _room: &RoomId,
+        _ctx: &MessageContext,
         _message: &VideoMessageEventContent,
     ) {
     }
+
+    /// Called when a user joins, leaves, is invited to or is banned from a room
+    ///
+    /// `ctx.sender` is the user that made the change, which is not necessarily `member`
+    async fn on_room_member(&self, _ctx: &MessageContext, _member: &Member) {}
+
+    /// Called when a message event that the Matrix SDK doesn't know about is received in a room
+    async fn on_room_custom_event(&self, _ctx: &MessageContext, _content: &CustomEventContent) {}
+
+    /// Called when a to-device event is received, such as a step in verifying a device
+    async fn on_to_device_event(&self, _event: &AnyToDeviceEvent) {}
+
+    /// Called when someone reacts to a message in a room
+    async fn on_reaction(&self, _ctx: &MessageContext, _reaction: &Reaction) {}
+
+    /// Called when someone clicks one of the buttons of a message that this plugin sent with
+    /// [`MessageContext::reply_with_buttons`]
+    ///
+    /// This is called in addition to `on_reaction`.
+    async fn on_button(&self, _ctx: &MessageContext, _reaction: &Reaction) {}
+
+    /// Called when someone removes their reaction that clicked one of the buttons of a message
+    /// that this plugin sent
+    ///
+    /// `ctx.sender` is the user that clicked the button, and `ctx.event_id` is their reaction.
+    async fn on_button_released(&self, _ctx: &MessageContext, _reaction: &Reaction) {}
 }
 
 impl PluginRegistry {
     /// Constructs and returns a new plugin registry
-    pub fn new(client: Client) -> PluginRegistry {
+    pub fn new(
+        client: Client,
+        config: Arc<Config>,
+        storage_backend: Arc<dyn StorageBackend>,
+        http: HttpClient,
+        history: Arc<History>,
+    ) -> PluginRegistry {
         PluginRegistry {
             client,
+            config,
+            storage_backend,
+            http,
+            history,
             plugins: vec![],
         }
     }
@@ -131,44 +219,80 @@ impl PluginRegistry {
     pub fn register<P: Plugin + 'static>(&mut self) -> Result<(), Error> {
         debug!("Registering plugin {}", std::any::type_name::<P>());
 
-        let plugin = P::new(self.client.clone());
+        let ctx = PluginContext {
+            client: self.client.clone(),
+            config: self.config.clone(),
+            storage: Storage::new(self.storage_backend.clone(), plugin_name::<P>()),
+            http: self.http.clone(),
+            history: self.history.clone(),
+        };
+        let plugin = P::new(ctx);
 
         match plugin {
             Ok(plugin) => {
                 debug!("Registered plugin {}", plugin.name());
+                health::set_plugin_state(plugin.name(), PluginState::Starting);
+
+                self.plugins.push(Arc::new(plugin));
+            }
+            Err(err) => {
+                let name = plugin_name::<P>();
 
-                self.plugins.push(Box::new(plugin));
+                error!("Failed to register plugin {}: {}", name, err);
+                metrics::PLUGIN_FAILURES
+                    .with_label_values(&[name, "register"])
+                    .inc();
             }
-            Err(err) => error!("Failed to register plugin: {}", err),
         }
 
         Ok(())
     }
 
     /// Returns a ref slice of all the plugins
-    pub fn plugins(&self) -> &[Box<dyn Plugin>] {
+    pub fn plugins(&self) -> &[Arc<dyn Plugin>] {
         self.plugins.as_ref()
     }
+
+    /// Starts all the registered plugins
+    pub async fn start(&self) -> Result<(), Error> {
+        for plugin in self.plugins.iter() {
+            debug!("Starting plugin {}", plugin.name());
+
+            match plugin.start().await {
+                Ok(()) => health::set_plugin_state(plugin.name(), PluginState::Started),
+                Err(err) => {
+                    error!("Failed to start plugin {}: {}", plugin.name(), err);
+                    metrics::PLUGIN_FAILURES
+                        .with_label_values(&[plugin.name(), "start"])
+                        .inc();
+                    health::set_plugin_state(plugin.name(), PluginState::Failed);
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::cache::Cache;
+    use crate::storage::MemoryBackend;
 
     struct TestPlugin {
-        client: Client,
+        ctx: PluginContext,
     }
 
     #[async_trait]
     impl Plugin for TestPlugin {
-        fn new(client: Client) -> Result<TestPlugin, Error> {
-            Ok(TestPlugin { client })
+        fn new(ctx: PluginContext) -> Result<TestPlugin, Error> {
+            Ok(TestPlugin { ctx })
         }
 
         async fn on_room_text_message(
             &self,
-            _user: &UserId,
-            _room: &RoomId,
+            _ctx: &MessageContext,
             _message: &TextMessageEventContent,
         ) {
         }
@@ -178,7 +302,22 @@ mod test {
     fn test_register_plugin() {
         let url: url::Url = "http://example.com".parse().unwrap();
         let client = Client::new(url).unwrap();
-        let mut registry = PluginRegistry::new(client);
+        let config: Config = toml::from_str(
+            r#"
+            [matrix]
+            homeserver = "http://example.com"
+            username = "meta"
+            password = "hunter2"
+            rooms = []
+            "#,
+        )
+        .unwrap();
+        let storage_backend = Arc::new(MemoryBackend::new());
+        let http_cache = Cache::new(&config.cache, Storage::new(storage_backend.clone(), "http"));
+        let http = HttpClient::new(config.http.clone(), http_cache).unwrap();
+        let history = Arc::new(History::new(client.clone(), config.history));
+        let mut registry =
+            PluginRegistry::new(client, Arc::new(config), storage_backend, http, history);
 
         registry.register::<TestPlugin>().unwrap();
     }
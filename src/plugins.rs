@@ -1,2 +1,11 @@
+pub mod admin;
 pub mod choices;
+pub mod encryption;
 pub mod google_search;
+pub mod greeter;
+pub mod karma;
+pub mod poll;
+pub mod quotes;
+pub mod reminders;
+pub mod sed;
+pub mod url_preview;
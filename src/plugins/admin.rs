@@ -0,0 +1,146 @@
+//! Commands that let admins manage the running bot
+//!
+//! Usage:
+//!
+//! ```text
+//! .loglevel <plugin> <level|reset>
+//! ```
+//!
+//! Plugins are named by the type name in their log lines, e.g. `UrlPreviewPlugin`.
+
+use async_trait::async_trait;
+use log::{error, info};
+use matrix_sdk::events::room::message::TextMessageEventContent;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::context::MessageContext;
+use crate::logging::{self, LoggingError};
+use crate::plugin::{Plugin, PluginContext};
+use crate::Error;
+
+#[derive(thiserror::Error, Debug)]
+pub enum AdminError {
+    #[error("usage: .loglevel <plugin> <trace|debug|info|warn|error|off|reset>")]
+    Usage,
+    #[error("only admins can do that")]
+    NotAdmin,
+    #[error("{0}")]
+    LoggingError(#[from] LoggingError),
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    /// Sets the log level of a plugin, or resets it to the default
+    LogLevel {
+        plugin: String,
+        level: Option<LevelFilter>,
+    },
+}
+
+pub struct AdminPlugin {
+    admins: Vec<String>,
+}
+
+/// Parses the body of a text message as an admin command
+///
+/// Returns `None` if the message isn't an admin command at all
+fn parse_command(body: &str) -> Option<Result<Command, AdminError>> {
+    let words: Vec<&str> = body.split_whitespace().collect();
+
+    match words.as_slice() {
+        [".loglevel", plugin, "reset"] => Some(Ok(Command::LogLevel {
+            plugin: plugin.to_string(),
+            level: None,
+        })),
+        [".loglevel", plugin, level] => Some(
+            logging::parse_level(level)
+                .map(|level| Command::LogLevel {
+                    plugin: plugin.to_string(),
+                    level: Some(level),
+                })
+                .map_err(AdminError::from),
+        ),
+        [".loglevel", ..] => Some(Err(AdminError::Usage)),
+        _ => None,
+    }
+}
+
+impl AdminPlugin {
+    fn handle_command(&self, ctx: &MessageContext, command: Command) -> Result<String, AdminError> {
+        if !self.admins.iter().any(|admin| admin == ctx.sender.as_str()) {
+            return Err(AdminError::NotAdmin);
+        }
+
+        match command {
+            Command::LogLevel { plugin, level } => {
+                logging::set_plugin_level(&plugin, level)?;
+
+                let reply = match level {
+                    Some(level) => format!("Log level of {} set to {}", plugin, level),
+                    None => format!("Log level of {} reset", plugin),
+                };
+
+                info!("{} by {}", reply, ctx.sender);
+
+                Ok(reply)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Plugin for AdminPlugin {
+    fn new(ctx: PluginContext) -> Result<Self, Error> {
+        Ok(AdminPlugin {
+            admins: ctx.config.matrix.admins.clone(),
+        })
+    }
+
+    async fn on_room_text_message(&self, ctx: &MessageContext, message: &TextMessageEventContent) {
+        if ctx.is_edit() {
+            return;
+        }
+
+        let result = match parse_command(&message.body) {
+            Some(Ok(command)) => ctx.reply_result(self.handle_command(ctx, command)).await,
+            Some(Err(err)) => ctx.reply_error(err).await,
+            None => return,
+        };
+
+        if let Err(err) = result {
+            error!("Failed to send reply: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse_command(".loglevel UrlPreviewPlugin debug")
+                .unwrap()
+                .unwrap(),
+            Command::LogLevel {
+                plugin: "UrlPreviewPlugin".to_string(),
+                level: Some(LevelFilter::DEBUG),
+            }
+        );
+        assert_eq!(
+            parse_command(".loglevel PollPlugin reset")
+                .unwrap()
+                .unwrap(),
+            Command::LogLevel {
+                plugin: "PollPlugin".to_string(),
+                level: None,
+            }
+        );
+        assert!(matches!(
+            parse_command(".loglevel PollPlugin loud"),
+            Some(Err(AdminError::LoggingError(LoggingError::InvalidLevel(_))))
+        ));
+        assert!(parse_command(".log").is_none());
+    }
+}
@@ -1,19 +1,12 @@
 use async_trait::async_trait;
 use lazy_static::lazy_static;
-use matrix_sdk::{
-    events::{
-        room::message::{
-            FormattedBody, MessageEventContent, MessageFormat, TextMessageEventContent,
-        },
-        AnyMessageEventContent,
-    },
-    Client,
-};
-use matrix_sdk_common::identifiers::{RoomId, UserId};
+use log::error;
+use matrix_sdk::events::room::message::TextMessageEventContent;
 use rand::seq::IteratorRandom;
 use regex::Regex;
 
-use crate::plugin::Plugin;
+use crate::context::MessageContext;
+use crate::plugin::{Plugin, PluginContext};
 use crate::Error;
 
 lazy_static! {
@@ -21,22 +14,15 @@ lazy_static! {
         Regex::new(r"(?i)^meta: (.*?) eller (.*?)\\?$").unwrap();
 }
 
-pub struct ChoicesPlugin {
-    client: Client,
-}
+pub struct ChoicesPlugin;
 
 #[async_trait]
 impl Plugin for ChoicesPlugin {
-    fn new(client: Client) -> Result<Self, Error> {
-        Ok(ChoicesPlugin { client })
+    fn new(_ctx: PluginContext) -> Result<Self, Error> {
+        Ok(ChoicesPlugin)
     }
 
-    async fn on_room_text_message(
-        &self,
-        user: &UserId,
-        room: &RoomId,
-        message: &TextMessageEventContent,
-    ) {
+    async fn on_room_text_message(&self, ctx: &MessageContext, message: &TextMessageEventContent) {
         if let Some(captures) = CHOICES_REQUEST.captures(&message.body) {
             match (captures.get(1), captures.get(2)) {
                 (Some(options), Some(last)) => {
@@ -48,6 +34,7 @@ impl Plugin for ChoicesPlugin {
                         .choose(&mut rand::thread_rng())
                         .unwrap_or_else(|| &"something went wrong");
 
+                    let user = &ctx.sender;
                     let plain_message = format!("{}: {}", user.localpart(), choice);
                     let html_message = format!(
                         "<a href=\"https://matrix.to/#/{}\">{}</a>: {}",
@@ -56,18 +43,9 @@ impl Plugin for ChoicesPlugin {
                         choice
                     );
 
-                    let content = AnyMessageEventContent::RoomMessage(MessageEventContent::Text(
-                        TextMessageEventContent {
-                            body: plain_message,
-                            formatted: Some(FormattedBody {
-                                body: html_message,
-                                format: MessageFormat::Html,
-                            }),
-                            relates_to: None,
-                        },
-                    ));
-
-                    self.client.room_send(room, content, None).await.unwrap();
+                    if let Err(err) = ctx.reply_html(plain_message, html_message).await {
+                        error!("Failed to send choice: {}", err);
+                    }
                 }
                 _ => {}
             }
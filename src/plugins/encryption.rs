@@ -0,0 +1,339 @@
+//! Lets admins verify the bot's device and export or import its room keys
+//!
+//! Usage:
+//!
+//! ```text
+//! .verify <device id>
+//! .keys <export|import>
+//! ```
+//!
+//! Verification uses SAS: the bot posts the emoji it sees, and the admin confirms that they
+//! match the ones shown in their own client by reacting with ✅, or cancels with ❌.
+//!
+//! NOTE: the revision of the Matrix SDK we build against doesn't support cross-signing or
+//! server-side key backup, and the revisions that do need tokio 1. Until we move to those, every
+//! new device of the bot has to be verified on its own, and room keys only survive rebuilding a
+//! bot host if the crypto store in the data directory is kept or the keys were exported.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use log::{debug, error};
+use matrix_sdk::{
+    events::{
+        room::message::{MessageEventContent, TextMessageEventContent},
+        AnyToDeviceEvent,
+    },
+    Client, CryptoStoreError, Sas,
+};
+use matrix_sdk_common::identifiers::{DeviceId, EventId, UserId};
+use tokio::sync::Mutex;
+
+use crate::context::MessageContext;
+use crate::plugin::{Plugin, PluginContext};
+use crate::reaction::Reaction;
+use crate::Error;
+
+/// The reaction that confirms that the emoji match
+const CONFIRM_KEY: &str = "✅";
+
+/// The reaction that cancels a verification
+const CANCEL_KEY: &str = "❌";
+
+/// The name of the file in the data directory that room keys are exported to and imported from
+const KEY_EXPORT_FILE: &str = "room-keys.txt";
+
+#[derive(thiserror::Error, Debug)]
+pub enum EncryptionError {
+    #[error("usage: .verify <device id> | .keys <export|import>")]
+    Usage,
+    #[error("only admins can do that")]
+    NotAdmin,
+    #[error("you have no device with id {0}")]
+    UnknownDevice(String),
+    #[error("key exports are disabled, because no passphrase is configured")]
+    ExportDisabled,
+    #[error("unable to read the crypto store")]
+    CryptoStoreError(#[from] CryptoStoreError),
+    #[error("matrix error")]
+    MatrixError(#[from] matrix_sdk::Error),
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Verify(String),
+    ExportKeys,
+    ImportKeys,
+}
+
+/// A verification that an admin started
+struct Verification {
+    sas: Sas,
+    admin: UserId,
+    /// The context of the command that started the verification, for posting updates
+    ctx: MessageContext,
+    /// The message with the emoji to compare, once they're known
+    prompt: Option<EventId>,
+}
+
+pub struct EncryptionPlugin {
+    client: Client,
+    admins: Vec<String>,
+    key_export_passphrase: Option<String>,
+    key_export_path: PathBuf,
+    /// The verifications in progress, by flow id
+    verifications: Mutex<HashMap<String, Verification>>,
+}
+
+/// Parses the body of a text message as a command
+///
+/// Returns `None` if the message isn't a command handled by this plugin at all
+fn parse_command(body: &str) -> Option<Result<Command, EncryptionError>> {
+    let words: Vec<&str> = body.split_whitespace().collect();
+
+    match words.as_slice() {
+        [".verify", device_id] => Some(Ok(Command::Verify(device_id.to_string()))),
+        [".keys", "export"] => Some(Ok(Command::ExportKeys)),
+        [".keys", "import"] => Some(Ok(Command::ImportKeys)),
+        [".verify", ..] | [".keys", ..] => Some(Err(EncryptionError::Usage)),
+        _ => None,
+    }
+}
+
+/// Formats the emoji of a verification for comparing with another client
+fn format_emoji(emoji: &[(&str, &str)]) -> String {
+    emoji
+        .iter()
+        .map(|(emoji, description)| format!("{} ({})", emoji, description))
+        .collect::<Vec<_>>()
+        .join("  ")
+}
+
+/// Returns the flow id of `event`, if it's a step of a verification
+fn verification_flow_id(event: &AnyToDeviceEvent) -> Option<&str> {
+    match event {
+        AnyToDeviceEvent::KeyVerificationKey(event) => Some(&event.content.transaction_id),
+        AnyToDeviceEvent::KeyVerificationMac(event) => Some(&event.content.transaction_id),
+        AnyToDeviceEvent::KeyVerificationCancel(event) => Some(&event.content.transaction_id),
+        _ => None,
+    }
+}
+
+impl EncryptionPlugin {
+    fn is_admin(&self, user: &UserId) -> bool {
+        self.admins.iter().any(|admin| admin == user.as_str())
+    }
+
+    async fn handle_command(
+        &self,
+        ctx: &MessageContext,
+        command: Command,
+    ) -> Result<String, EncryptionError> {
+        if !self.is_admin(&ctx.sender) {
+            return Err(EncryptionError::NotAdmin);
+        }
+
+        match command {
+            Command::Verify(device_id) => {
+                let device = self
+                    .client
+                    .get_device(&ctx.sender, <&DeviceId>::from(device_id.as_str()))
+                    .await?
+                    .ok_or_else(|| EncryptionError::UnknownDevice(device_id.clone()))?;
+                let sas = device.start_verification().await?;
+
+                debug!("Started verifying {} of {}", device_id, ctx.sender);
+
+                self.verifications.lock().await.insert(
+                    sas.flow_id().to_string(),
+                    Verification {
+                        sas,
+                        admin: ctx.sender.clone(),
+                        ctx: ctx.clone(),
+                        prompt: None,
+                    },
+                );
+
+                Ok(format!(
+                    "Verifying device {}, accept the request in your client",
+                    device_id
+                ))
+            }
+            Command::ExportKeys => {
+                self.client
+                    .export_keys(self.key_export_path.clone(), self.passphrase()?, |_| true)
+                    .await?;
+
+                Ok(format!("Exported room keys to {}", KEY_EXPORT_FILE))
+            }
+            Command::ImportKeys => {
+                self.client
+                    .import_keys(self.key_export_path.clone(), self.passphrase()?)
+                    .await?;
+
+                Ok(format!("Imported room keys from {}", KEY_EXPORT_FILE))
+            }
+        }
+    }
+
+    /// Returns the passphrase to encrypt and decrypt room key exports with
+    fn passphrase(&self) -> Result<&str, EncryptionError> {
+        self.key_export_passphrase
+            .as_deref()
+            .ok_or(EncryptionError::ExportDisabled)
+    }
+
+    /// Posts the emoji to compare, once both sides have exchanged keys
+    async fn prompt(&self, verification: &mut Verification) {
+        let emoji = match verification.sas.emoji() {
+            Some(emoji) => emoji,
+            None => return,
+        };
+        let body = format!(
+            "{}: do these match your client? {}",
+            verification.admin.localpart(),
+            format_emoji(&emoji)
+        );
+        let content = MessageEventContent::Text(TextMessageEventContent::plain(body));
+
+        match verification
+            .ctx
+            .reply_with_buttons(self, content, &[CONFIRM_KEY, CANCEL_KEY])
+            .await
+        {
+            Ok(event_id) => verification.prompt = Some(event_id),
+            Err(err) => error!("Failed to send verification emoji: {}", err),
+        }
+    }
+
+    /// Reports on a verification if it's finished, returning whether it is
+    async fn finish(&self, verification: &Verification) -> bool {
+        let message = if verification.sas.is_done() {
+            "Device verified"
+        } else if verification.sas.is_canceled() {
+            "Verification cancelled"
+        } else {
+            return false;
+        };
+
+        if let Err(err) = verification.ctx.reply_text(message).await {
+            error!("Failed to send verification result: {}", err);
+        }
+
+        true
+    }
+}
+
+#[async_trait]
+impl Plugin for EncryptionPlugin {
+    fn new(ctx: PluginContext) -> Result<Self, Error> {
+        Ok(EncryptionPlugin {
+            client: ctx.client,
+            admins: ctx.config.matrix.admins.clone(),
+            key_export_passphrase: ctx.config.encryption.key_export_passphrase.clone(),
+            key_export_path: ctx.config.data_dir.join(KEY_EXPORT_FILE),
+            verifications: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn ordered(&self) -> bool {
+        // The steps of a verification have to be handled in the order they were sent
+        true
+    }
+
+    async fn on_room_text_message(&self, ctx: &MessageContext, message: &TextMessageEventContent) {
+        if ctx.is_edit() {
+            return;
+        }
+
+        let result = match parse_command(&message.body) {
+            Some(Ok(command)) => {
+                ctx.reply_result(self.handle_command(ctx, command).await)
+                    .await
+            }
+            Some(Err(err)) => ctx.reply_error(err).await,
+            None => return,
+        };
+
+        if let Err(err) = result {
+            error!("Failed to send reply: {}", err);
+        }
+    }
+
+    async fn on_to_device_event(&self, event: &AnyToDeviceEvent) {
+        let flow_id = match verification_flow_id(event) {
+            Some(flow_id) => flow_id,
+            None => return,
+        };
+        let mut verifications = self.verifications.lock().await;
+        let verification = match verifications.get_mut(flow_id) {
+            Some(verification) => verification,
+            None => return,
+        };
+
+        if let AnyToDeviceEvent::KeyVerificationKey(_) = event {
+            self.prompt(verification).await;
+        }
+
+        if self.finish(verification).await {
+            verifications.remove(flow_id);
+        }
+    }
+
+    async fn on_button(&self, ctx: &MessageContext, reaction: &Reaction) {
+        let mut verifications = self.verifications.lock().await;
+        let flow_id = match verifications.iter().find(|(_, verification)| {
+            verification.prompt.as_ref() == Some(&reaction.event_id)
+                && verification.admin == ctx.sender
+        }) {
+            Some((flow_id, _)) => flow_id.clone(),
+            None => return,
+        };
+        let verification = &verifications[&flow_id];
+
+        let result = if reaction.key == CONFIRM_KEY {
+            verification.sas.confirm().await
+        } else {
+            verification.sas.cancel().await
+        };
+
+        if let Err(err) = result {
+            error!("Failed to answer verification {}: {}", flow_id, err);
+        }
+
+        if self.finish(verification).await {
+            verifications.remove(&flow_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse_command(".verify ABCDEFGH").unwrap().unwrap(),
+            Command::Verify("ABCDEFGH".to_string())
+        );
+        assert_eq!(
+            parse_command(".keys export").unwrap().unwrap(),
+            Command::ExportKeys
+        );
+        assert!(matches!(
+            parse_command(".keys delete"),
+            Some(Err(EncryptionError::Usage))
+        ));
+        assert!(parse_command(".verifying").is_none());
+    }
+
+    #[test]
+    fn test_format_emoji() {
+        assert_eq!(
+            format_emoji(&[("🐶", "Dog"), ("🔑", "Key")]),
+            "🐶 (Dog)  🔑 (Key)"
+        );
+    }
+}
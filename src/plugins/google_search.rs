@@ -1,20 +1,15 @@
 use async_trait::async_trait;
 use lazy_static::lazy_static;
 use log::error;
-use matrix_sdk::{
-    events::{
-        room::message::{
-            FormattedBody, MessageEventContent, MessageFormat, TextMessageEventContent,
-        },
-        AnyMessageEventContent,
-    },
-    Client,
-};
-use matrix_sdk_common::identifiers::{RoomId, UserId};
+use matrix_sdk::events::room::message::TextMessageEventContent;
 use scraper::{ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::plugin::Plugin;
+use crate::cache::{self, Cache};
+use crate::context::MessageContext;
+use crate::http::{HttpClient, HttpError};
+use crate::plugin::{Plugin, PluginContext};
 use crate::Error;
 
 lazy_static! {
@@ -25,8 +20,9 @@ lazy_static! {
 }
 
 pub struct GoogleSearchPlugin {
-    client: Client,
-    http_client: reqwest::Client,
+    http: HttpClient,
+    /// Search results by normalized query
+    cache: Cache,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -37,13 +33,13 @@ pub enum GoogleSearchError {
     NoSearchResults,
     #[error("missing expected element")]
     MissingElement(&'static str),
-    #[error("http client error")]
-    ReqwestError(#[from] reqwest::Error),
+    #[error("http error")]
+    HttpError(#[from] HttpError),
     #[error("unable to parse result url: {0}")]
     UrlParseError(#[from] url::ParseError),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct SearchResult {
     title: String,
     url: Url,
@@ -80,15 +76,30 @@ impl SearchResult {
 
 impl GoogleSearchPlugin {
     async fn search(&self, query: &str) -> Result<Option<SearchResult>, GoogleSearchError> {
-        let req = self
-            .http_client
-            .get("https://www.google.dk/search")
-            .query(&[("q", query), ("hl", "en")])
+        // Only the cache key is normalized, Google gets the query as it was written
+        let key = cache::normalize_query(query);
+
+        if let Some(result) = self.cache.get(&key).await {
+            return Ok(result);
+        }
+
+        let url = Url::parse_with_params(
+            "https://www.google.dk/search",
+            &[("q", query), ("hl", "en")],
+        )?;
+        // The parsed results are cached instead of the page
+        let response = self
+            .http
+            .get(url)
+            .no_cache()
             .send()
-            .await?;
-        let body = req.text().await?;
+            .await?
+            .error_for_status()?;
+        let result = Self::parse_search_page(&response.text())?;
+
+        self.cache.insert(&key, &result).await;
 
-        Self::parse_search_page(&body)
+        Ok(result)
     }
 
     fn parse_search_page(body: &str) -> Result<Option<SearchResult>, GoogleSearchError> {
@@ -104,45 +115,32 @@ impl GoogleSearchPlugin {
 
 #[async_trait]
 impl Plugin for GoogleSearchPlugin {
-    fn new(client: Client) -> Result<Self, Error> {
-        let http_client = reqwest::Client::builder()
-            .user_agent("Mozilla/5.0 (X11; Linux x86_64; rv:80.0) Gecko/20100101 Firefox/80.0")
-            .gzip(true)
-            .build()
-            .map_err(Error::ReqwestBuildError)?;
-
+    fn new(ctx: PluginContext) -> Result<Self, Error> {
         Ok(GoogleSearchPlugin {
-            client,
-            http_client,
+            http: ctx.http,
+            cache: Cache::new(&ctx.config.cache, ctx.storage),
         })
     }
 
-    async fn on_room_text_message(
-        &self,
-        _user: &UserId,
-        room: &RoomId,
-        message: &TextMessageEventContent,
-    ) {
+    async fn on_room_text_message(&self, ctx: &MessageContext, message: &TextMessageEventContent) {
         if message.body.starts_with(".g ") {
-            let result = self.search(&message.body[3..]).await;
-            let message = match result {
+            let message = match self.search(&message.body[3..]).await {
                 Ok(Some(result)) => format!("{} - {}", result.title, result.url),
                 Ok(None) => format!("No results"),
-                Err(err) => format!("Error: {}", err),
+                Err(err) => {
+                    if let Err(err) = ctx.reply_error(err).await {
+                        error!("Failed to send search error: {}", err);
+                    }
+
+                    return;
+                }
             };
 
-            let content = AnyMessageEventContent::RoomMessage(MessageEventContent::Text(
-                TextMessageEventContent {
-                    body: message.clone(),
-                    formatted: Some(FormattedBody {
-                        body: message,
-                        format: MessageFormat::Html,
-                    }),
-                    relates_to: None,
-                },
-            ));
-
-            self.client.room_send(room, content, None).await.unwrap();
+            ctx.command_succeeded();
+
+            if let Err(err) = ctx.reply_html(message.clone(), message).await {
+                error!("Failed to send search result: {}", err);
+            }
         }
     }
 }
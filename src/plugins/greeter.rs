@@ -0,0 +1,70 @@
+//! Welcomes new members of rooms with a configured message
+
+use async_trait::async_trait;
+use log::error;
+
+use crate::config::GreeterConfig;
+use crate::context::MessageContext;
+use crate::membership::{Member, MembershipChange};
+use crate::plugin::{Plugin, PluginContext};
+use crate::Error;
+
+pub struct GreeterPlugin {
+    config: GreeterConfig,
+}
+
+/// Fills in the name of the new member in a welcome message
+fn format_greeting(template: &str, member: &Member) -> String {
+    template.replace("{user}", member.name())
+}
+
+#[async_trait]
+impl Plugin for GreeterPlugin {
+    fn new(ctx: PluginContext) -> Result<Self, Error> {
+        Ok(GreeterPlugin {
+            config: ctx.config.plugins.greeter.clone(),
+        })
+    }
+
+    async fn on_room_member(&self, ctx: &MessageContext, member: &Member) {
+        if member.change != MembershipChange::Joined {
+            return;
+        }
+
+        let template = match self.config.messages.get(ctx.room_id.as_str()) {
+            Some(template) => template,
+            None => return,
+        };
+
+        if let Err(err) = ctx.reply_text(format_greeting(template, member)).await {
+            error!("Failed to greet {}: {}", member.user_id, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk_common::identifiers::UserId;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_format_greeting() {
+        let mut member = Member {
+            user_id: UserId::try_from("@alice:example.com").unwrap(),
+            display_name: Some("Alice".to_string()),
+            change: MembershipChange::Joined,
+        };
+
+        assert_eq!(
+            format_greeting("Welcome, {user}!", &member),
+            "Welcome, Alice!"
+        );
+
+        member.display_name = None;
+        assert_eq!(
+            format_greeting("Welcome, {user}!", &member),
+            "Welcome, @alice:example.com!"
+        );
+    }
+}
@@ -0,0 +1,480 @@
+//! Keeps track of karma that people give each other with `nick++` and `nick--`
+//!
+//! Usage:
+//!
+//! ```text
+//! <user>++
+//! <user>--
+//! .karma [user]
+//! .karma top
+//! ```
+//!
+//! Users can be named by their user id, their localpart or their display name, which may contain
+//! spaces. Names that don't belong to exactly one member of the room are ignored, so `c++` is
+//! only a vote if someone is actually called `c`.
+//!
+//! Karma is kept per room. Nobody can change their own karma, and everyone has to wait for the
+//! configured cooldown between votes.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::error;
+use matrix_sdk::{events::room::message::TextMessageEventContent, Client};
+use matrix_sdk_common::identifiers::{RoomId, UserId};
+
+use crate::context::{strip_reply_fallback, MessageContext};
+use crate::plugin::{Plugin, PluginContext};
+use crate::storage::{Storage, StorageError};
+use crate::Error;
+
+/// The most words a display name can consist of to be voted for
+const MAX_NAME_WORDS: usize = 3;
+
+/// The number of users to list on the leaderboard
+const LEADERBOARD_SIZE: usize = 10;
+
+#[derive(thiserror::Error, Debug)]
+pub enum KarmaError {
+    #[error("nobody here is called `{0}'")]
+    UnknownUser(String),
+    #[error("you can't change your own karma")]
+    OwnKarma,
+    #[error("you can vote again in {0} seconds")]
+    Cooldown(u64),
+    #[error("unable to read or write karma")]
+    StorageError(#[from] StorageError),
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    /// Shows the karma of the named user, or of the sender
+    Show(Option<String>),
+    Top,
+}
+
+/// A `++` or `--` in a message
+#[derive(Debug, PartialEq)]
+struct Vote {
+    /// The names that the vote may be for, longest first
+    candidates: Vec<String>,
+    delta: i64,
+}
+
+/// A member of a room and their display name there
+type Member = (UserId, Option<String>);
+
+pub struct KarmaPlugin {
+    client: Client,
+    storage: Storage,
+    cooldown: Duration,
+    /// When each user last voted in each room
+    last_votes: Mutex<HashMap<(RoomId, UserId), Instant>>,
+}
+
+/// Parses the body of a text message as a command
+///
+/// Returns `None` if the message isn't a karma command at all
+fn parse_command(body: &str) -> Option<Command> {
+    let rest = body.trim().strip_prefix(".karma")?;
+
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    match rest.trim() {
+        "" => Some(Command::Show(None)),
+        "top" => Some(Command::Top),
+        name => Some(Command::Show(Some(name.to_string()))),
+    }
+}
+
+/// Returns whether `word` ends a vote or a part of a sentence, so no name continues past it
+fn ends_clause(word: &str) -> bool {
+    word.ends_with("++")
+        || word.ends_with("--")
+        || word.ends_with(&[',', '.', '!', '?', ';', ':'][..])
+}
+
+/// Returns all the votes in the body of a message
+fn parse_votes(body: &str) -> Vec<Vote> {
+    let mut votes = vec![];
+
+    for line in body.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        for (i, word) in words.iter().enumerate() {
+            let word = word.trim_end_matches(&[',', '.', '!', '?', ';', ')'][..]);
+            let (name, delta) = if let Some(name) = word.strip_suffix("++") {
+                (name, 1)
+            } else if let Some(name) = word.strip_suffix("--") {
+                (name, -1)
+            } else {
+                continue;
+            };
+
+            if name.is_empty() {
+                continue;
+            }
+
+            // Display names can contain spaces, so the words before may be part of the name, up to
+            // the previous vote or punctuation
+            let mut first = i;
+
+            while first > 0 && i - first < MAX_NAME_WORDS - 1 && !ends_clause(words[first - 1]) {
+                first -= 1;
+            }
+
+            let mut candidates: Vec<String> = (first..i)
+                .map(|start| {
+                    let mut parts = words[start..i].to_vec();
+                    parts.push(name);
+                    parts.join(" ")
+                })
+                .collect();
+            candidates.push(name.to_string());
+
+            votes.push(Vote { candidates, delta });
+        }
+    }
+
+    votes
+}
+
+/// Returns the member of the room that `name` refers to, by user id, localpart or display name
+///
+/// Returns `None` if nobody or more than one member goes by `name`.
+fn resolve<'a>(name: &str, members: &'a [Member]) -> Option<&'a UserId> {
+    if let Ok(user_id) = UserId::try_from(name) {
+        return members
+            .iter()
+            .map(|(member, _)| member)
+            .find(|member| **member == user_id);
+    }
+
+    let name = name.trim_start_matches('@').to_lowercase();
+    let found: Vec<&UserId> = members
+        .iter()
+        .filter(|(user_id, display_name)| {
+            user_id.localpart().to_lowercase() == name
+                || display_name
+                    .as_ref()
+                    .map_or(false, |display_name| display_name.to_lowercase() == name)
+        })
+        .map(|(user_id, _)| user_id)
+        .collect();
+
+    match found.as_slice() {
+        [user_id] => Some(*user_id),
+        _ => None,
+    }
+}
+
+/// Returns how `user_id` is shown in replies
+fn display_name(user_id: &UserId, members: &[Member]) -> String {
+    members
+        .iter()
+        .find(|(member, _)| member == user_id)
+        .and_then(|(_, display_name)| display_name.clone())
+        .unwrap_or_else(|| user_id.localpart().to_string())
+}
+
+/// Returns the storage key of the karma of `user_id` in `room_id`
+fn karma_key(room_id: &RoomId, user_id: &UserId) -> String {
+    format!("{}/{}", room_id, user_id)
+}
+
+impl KarmaPlugin {
+    /// Returns the joined members of `room_id` with their display names
+    async fn members(&self, room_id: &RoomId) -> Vec<Member> {
+        let room = match self.client.get_joined_room(room_id).await {
+            Some(room) => room,
+            None => return vec![],
+        };
+        let room = room.read().await;
+
+        room.joined_members
+            .values()
+            .map(|member| (member.user_id.clone(), member.display_name.clone()))
+            .collect()
+    }
+
+    /// Returns the karma of `user_id` in `room_id`
+    async fn karma(&self, room_id: &RoomId, user_id: &UserId) -> Result<i64, KarmaError> {
+        let karma = self
+            .storage
+            .get::<i64>(&karma_key(room_id, user_id))
+            .await?;
+
+        Ok(karma.unwrap_or_default())
+    }
+
+    /// Records that the sender of `ctx` votes now, unless they voted too recently
+    fn start_cooldown(&self, ctx: &MessageContext) -> Result<(), KarmaError> {
+        let mut last_votes = self.last_votes.lock().unwrap();
+        let key = (ctx.room_id.clone(), ctx.sender.clone());
+        let now = Instant::now();
+
+        if let Some(last_vote) = last_votes.get(&key) {
+            let elapsed = now.duration_since(*last_vote);
+
+            if elapsed < self.cooldown {
+                let remaining = self.cooldown - elapsed;
+
+                return Err(KarmaError::Cooldown(remaining.as_secs().max(1)));
+            }
+        }
+
+        last_votes.insert(key, now);
+
+        Ok(())
+    }
+
+    /// Applies the votes in a message and returns the new totals of the users they were for
+    async fn handle_votes(
+        &self,
+        ctx: &MessageContext,
+        votes: Vec<Vote>,
+    ) -> Result<Option<String>, KarmaError> {
+        let members = self.members(&ctx.room_id).await;
+        let mut deltas: Vec<(UserId, i64)> = vec![];
+
+        for vote in votes {
+            let user_id = vote
+                .candidates
+                .iter()
+                .find_map(|name| resolve(name, &members));
+
+            // Only the first vote for each user in a message counts
+            if let Some(user_id) = user_id {
+                if !deltas.iter().any(|(other, _)| other == user_id) {
+                    deltas.push((user_id.clone(), vote.delta));
+                }
+            }
+        }
+
+        if deltas.is_empty() {
+            return Ok(None);
+        }
+
+        if deltas.iter().any(|(user_id, _)| *user_id == ctx.sender) {
+            return Err(KarmaError::OwnKarma);
+        }
+
+        self.start_cooldown(ctx)?;
+
+        let mut lines = vec![];
+
+        for (user_id, delta) in deltas {
+            let karma = self
+                .storage
+                .update(
+                    &karma_key(&ctx.room_id, &user_id),
+                    move |karma: Option<i64>| Some(karma.unwrap_or(0) + delta),
+                )
+                .await?
+                .unwrap_or_default();
+
+            lines.push(format!(
+                "{} now has {} karma",
+                display_name(&user_id, &members),
+                karma
+            ));
+        }
+
+        Ok(Some(lines.join("\n")))
+    }
+
+    async fn handle_command(
+        &self,
+        ctx: &MessageContext,
+        command: Command,
+    ) -> Result<String, KarmaError> {
+        let members = self.members(&ctx.room_id).await;
+
+        match command {
+            Command::Show(name) => {
+                let user_id = match name {
+                    Some(name) => resolve(&name, &members)
+                        .cloned()
+                        .ok_or(KarmaError::UnknownUser(name))?,
+                    None => ctx.sender.clone(),
+                };
+                let karma = self.karma(&ctx.room_id, &user_id).await?;
+
+                Ok(format!(
+                    "{} has {} karma",
+                    display_name(&user_id, &members),
+                    karma
+                ))
+            }
+            Command::Top => {
+                let prefix = format!("{}/", ctx.room_id);
+                let mut leaderboard: Vec<(UserId, i64)> = self
+                    .storage
+                    .list::<i64>(&prefix)
+                    .await?
+                    .into_iter()
+                    .filter_map(|(key, karma)| {
+                        let user_id = UserId::try_from(key.strip_prefix(&prefix)?).ok()?;
+
+                        Some((user_id, karma))
+                    })
+                    .collect();
+
+                if leaderboard.is_empty() {
+                    return Ok("Nobody has any karma yet".to_string());
+                }
+
+                leaderboard.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+                let lines: Vec<String> = leaderboard
+                    .iter()
+                    .take(LEADERBOARD_SIZE)
+                    .enumerate()
+                    .map(|(i, (user_id, karma))| {
+                        format!("{}. {}: {}", i + 1, display_name(user_id, &members), karma)
+                    })
+                    .collect();
+
+                Ok(lines.join("\n"))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Plugin for KarmaPlugin {
+    fn new(ctx: PluginContext) -> Result<Self, Error> {
+        Ok(KarmaPlugin {
+            client: ctx.client,
+            storage: ctx.storage,
+            cooldown: Duration::from_secs(ctx.config.plugins.karma.cooldown),
+            last_votes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn on_room_text_message(&self, ctx: &MessageContext, message: &TextMessageEventContent) {
+        // Editing a message must not count its votes twice
+        if ctx.is_edit() {
+            return;
+        }
+
+        // Votes in the quote of the message being replied to were already counted
+        let body = strip_reply_fallback(&message.body);
+        let result = match parse_command(body) {
+            Some(command) => {
+                ctx.reply_result(self.handle_command(ctx, command).await)
+                    .await
+            }
+            None => {
+                let votes = parse_votes(body);
+
+                if votes.is_empty() {
+                    return;
+                }
+
+                match self.handle_votes(ctx, votes).await {
+                    Ok(Some(reply)) => ctx.reply_result::<KarmaError>(Ok(reply)).await,
+                    Ok(None) => return,
+                    Err(err) => ctx.reply_error(err).await,
+                }
+            }
+        };
+
+        if let Err(err) = result {
+            error!("Failed to send reply: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_id(id: &str) -> UserId {
+        UserId::try_from(id).unwrap()
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command(".karma"), Some(Command::Show(None)));
+        assert_eq!(parse_command(".karma top"), Some(Command::Top));
+        assert_eq!(
+            parse_command(".karma Alice Smith").unwrap(),
+            Command::Show(Some("Alice Smith".to_string()))
+        );
+        assert!(parse_command(".karmas").is_none());
+    }
+
+    #[test]
+    fn test_parse_votes() {
+        assert_eq!(
+            parse_votes("thanks Alice Smith++, and bob--"),
+            vec![
+                Vote {
+                    candidates: vec![
+                        "thanks Alice Smith".to_string(),
+                        "Alice Smith".to_string(),
+                        "Smith".to_string(),
+                    ],
+                    delta: 1,
+                },
+                Vote {
+                    candidates: vec!["and bob".to_string(), "bob".to_string()],
+                    delta: -1,
+                },
+            ]
+        );
+        assert!(parse_votes("i -- for one -- disagree").is_empty());
+        assert!(parse_votes("no votes here").is_empty());
+        assert!(
+            parse_votes(strip_reply_fallback("> <@x:example.com> alice++\n\nindeed")).is_empty()
+        );
+        assert_eq!(
+            parse_votes(strip_reply_fallback("> <@x:example.com> alice++\n\nbob++")),
+            vec![Vote {
+                candidates: vec!["bob".to_string()],
+                delta: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let members = vec![
+            (
+                user_id("@alice:example.com"),
+                Some("Alice Smith".to_string()),
+            ),
+            (user_id("@bob:example.com"), None),
+            (user_id("@carol:example.com"), Some("Sam".to_string())),
+            (user_id("@dave:example.com"), Some("Sam".to_string())),
+        ];
+
+        assert_eq!(
+            resolve("alice smith", &members),
+            Some(&user_id("@alice:example.com"))
+        );
+        assert_eq!(
+            resolve("@alice:example.com", &members),
+            Some(&user_id("@alice:example.com"))
+        );
+        assert_eq!(
+            resolve("@bob", &members),
+            Some(&user_id("@bob:example.com"))
+        );
+        assert_eq!(resolve("Sam", &members), None);
+        assert_eq!(resolve("@eve:example.com", &members), None);
+        assert_eq!(resolve("c", &members), None);
+
+        assert_eq!(
+            display_name(&user_id("@alice:example.com"), &members),
+            "Alice Smith"
+        );
+        assert_eq!(display_name(&user_id("@bob:example.com"), &members), "bob");
+    }
+}
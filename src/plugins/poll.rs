@@ -0,0 +1,702 @@
+//! Polls that are voted on with reactions, or natively in clients that support MSC3381
+//!
+//! Usage:
+//!
+//! ```text
+//! .poll [<duration>] "<question>" <option>, <option>[, ...]
+//! .poll results
+//! .poll close
+//! ```
+//!
+//! Every user has a single vote, and voting again moves it to the new option. Removing the
+//! reaction takes the vote back.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local, Utc};
+use log::{debug, error};
+use matrix_sdk::{
+    events::{
+        custom::CustomEventContent,
+        room::message::{MessageEventContent, TextMessageEventContent},
+        AnyMessageEventContent,
+    },
+    Client,
+};
+use matrix_sdk_common::identifiers::{EventId, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use tokio::time;
+
+use crate::config::PollConfig;
+use crate::context::{self, MessageContext};
+use crate::duration::{format_duration, parse_duration, DURATION};
+use crate::plugin::{Plugin, PluginContext};
+use crate::reaction::Reaction;
+use crate::storage::{Storage, StorageError};
+use crate::Error;
+
+/// The reactions used to vote for each option, in order
+const OPTION_KEYS: [&str; 10] = ["1️⃣", "2️⃣", "3️⃣", "4️⃣", "5️⃣", "6️⃣", "7️⃣", "8️⃣", "9️⃣", "🔟"];
+
+/// The event type of native polls that we send
+const POLL_START_EVENT_TYPE: &str = "org.matrix.msc3381.poll.start";
+
+/// The event type of the event that ends a native poll
+const POLL_END_EVENT_TYPE: &str = "org.matrix.msc3381.poll.end";
+
+/// The event types of votes in native polls
+const POLL_RESPONSE_EVENT_TYPES: [&str; 2] =
+    ["org.matrix.msc3381.poll.response", "m.poll.response"];
+
+#[derive(thiserror::Error, Debug)]
+pub enum PollError {
+    #[error(r#"usage: .poll [<duration>] "<question>" <option>, <option>[, ...] | .poll results | .poll close"#)]
+    Usage,
+    #[error("invalid duration `{0}'")]
+    InvalidDuration(String),
+    #[error("a poll needs between 2 and {} options", OPTION_KEYS.len())]
+    OptionCount,
+    #[error("there is no poll in this room")]
+    NoPoll,
+    #[error("only the creator of the poll can close it")]
+    NotCreator,
+    #[error("the poll is already closed")]
+    AlreadyClosed,
+    #[error("unable to read or write polls")]
+    StorageError(#[from] StorageError),
+    #[error("matrix error")]
+    MatrixError(#[from] matrix_sdk::Error),
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Start {
+        question: String,
+        options: Vec<String>,
+        duration: Option<chrono::Duration>,
+    },
+    Results,
+    Close,
+}
+
+/// A single stored poll
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Poll {
+    room_id: RoomId,
+    creator: UserId,
+    question: String,
+    options: Vec<String>,
+    /// The index of the option each user voted for, by user id
+    votes: BTreeMap<String, usize>,
+    /// When the poll closes by itself, if ever
+    deadline: Option<DateTime<Utc>>,
+    closed: bool,
+    /// Whether the poll was sent as a native MSC3381 poll
+    native: bool,
+}
+
+struct Inner {
+    client: Client,
+    storage: Storage,
+    config: PollConfig,
+}
+
+pub struct PollPlugin {
+    inner: Arc<Inner>,
+}
+
+/// Parses the body of a text message as a poll command
+///
+/// Returns `None` if the message isn't a poll command at all
+fn parse_command(body: &str) -> Option<Result<Command, PollError>> {
+    let rest = match body.strip_prefix(".poll") {
+        Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => rest.trim(),
+        _ => return None,
+    };
+
+    Some(parse_arguments(rest))
+}
+
+fn parse_arguments(rest: &str) -> Result<Command, PollError> {
+    match rest {
+        "results" => return Ok(Command::Results),
+        "close" => return Ok(Command::Close),
+        _ => {}
+    }
+
+    let mut parts = rest.splitn(2, char::is_whitespace);
+    let first = parts.next().unwrap_or_default();
+
+    // Anything else before the question is a mistake in the command, not a duration
+    let (duration, rest) = if DURATION.is_match(first) {
+        let duration =
+            parse_duration(first).ok_or_else(|| PollError::InvalidDuration(first.to_string()))?;
+
+        (
+            Some(duration),
+            parts.next().unwrap_or_default().trim_start(),
+        )
+    } else {
+        (None, rest)
+    };
+
+    let rest = rest.strip_prefix('"').ok_or(PollError::Usage)?;
+    let end = rest.find('"').ok_or(PollError::Usage)?;
+    let question = rest[..end].trim();
+
+    if question.is_empty() {
+        return Err(PollError::Usage);
+    }
+
+    let options: Vec<String> = rest[end + 1..]
+        .split(',')
+        .map(|option| option.trim().to_string())
+        .filter(|option| !option.is_empty())
+        .collect();
+
+    if options.len() < 2 || options.len() > OPTION_KEYS.len() {
+        return Err(PollError::OptionCount);
+    }
+
+    Ok(Command::Start {
+        question: question.to_string(),
+        options,
+        duration,
+    })
+}
+
+/// Parses a vote in a native poll, returning the poll and the index of the chosen option
+fn parse_response(content: &CustomEventContent) -> Option<(EventId, usize)> {
+    if !POLL_RESPONSE_EVENT_TYPES.contains(&content.event_type.as_str()) {
+        return None;
+    }
+
+    let poll = content.json.pointer("/m.relates_to/event_id")?.as_str()?;
+    let answers = content
+        .json
+        .pointer("/org.matrix.msc3381.poll.response/answers")
+        .or_else(|| content.json.get("m.selections"))?;
+    let answer = answers.get(0)?.as_str()?.parse::<usize>().ok()?;
+
+    Some((EventId::try_from(poll).ok()?, answer.checked_sub(1)?))
+}
+
+/// Returns the storage key of the poll `event_id`
+fn poll_key(event_id: &EventId) -> String {
+    format!("poll/{}", event_id)
+}
+
+/// Returns the storage key of the id of the latest poll in `room_id`
+fn latest_key(room_id: &RoomId) -> String {
+    format!("latest/{}", room_id)
+}
+
+impl Poll {
+    /// Returns the number of votes for each option
+    fn tally(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.options.len()];
+
+        for &option in self.votes.values() {
+            if let Some(count) = counts.get_mut(option) {
+                *count += 1;
+            }
+        }
+
+        counts
+    }
+
+    /// Returns the message that presents the poll to voters
+    fn to_message(&self) -> String {
+        let mut message = format!("Poll: {}", self.question);
+
+        for (key, option) in OPTION_KEYS.iter().zip(self.options.iter()) {
+            message.push_str(&format!("\n{} {}", key, option));
+        }
+
+        if let Some(deadline) = self.deadline {
+            let deadline = deadline.with_timezone(&Local).format("%Y-%m-%d %H:%M");
+
+            message.push_str(&format!("\nCloses at {}", deadline));
+        }
+
+        message
+    }
+
+    /// Returns the content of a native MSC3381 poll, with a text fallback for other clients
+    fn to_native_content(&self) -> AnyMessageEventContent {
+        let answers: Vec<JsonValue> = self
+            .options
+            .iter()
+            .enumerate()
+            .map(|(i, option)| {
+                json!({
+                    "id": (i + 1).to_string(),
+                    "org.matrix.msc1767.text": option,
+                })
+            })
+            .collect();
+
+        AnyMessageEventContent::Custom(CustomEventContent {
+            event_type: POLL_START_EVENT_TYPE.to_string(),
+            json: json!({
+                POLL_START_EVENT_TYPE: {
+                    "question": { "org.matrix.msc1767.text": self.question },
+                    "kind": "org.matrix.msc3381.poll.disclosed",
+                    "max_selections": 1,
+                    "answers": answers,
+                },
+                "org.matrix.msc1767.text": self.to_message(),
+            }),
+        })
+    }
+
+    /// Returns the current results of the poll
+    fn results(&self) -> String {
+        let counts = self.tally();
+        let total = self.votes.len();
+        let mut message = format!(
+            "{} ({} vote{}{})",
+            self.question,
+            total,
+            if total == 1 { "" } else { "s" },
+            if self.closed { ", closed" } else { "" }
+        );
+
+        for (i, (option, count)) in self.options.iter().zip(counts.iter()).enumerate() {
+            message.push_str(&format!("\n{}. {}: {}", i + 1, option, count));
+
+            if total > 0 {
+                message.push_str(&format!(" ({}%)", count * 100 / total));
+            }
+        }
+
+        message
+    }
+}
+
+impl Inner {
+    async fn handle_command(
+        self: &Arc<Self>,
+        plugin: &PollPlugin,
+        ctx: &MessageContext,
+        command: Command,
+    ) -> Result<Option<String>, PollError> {
+        match command {
+            Command::Start {
+                question,
+                options,
+                duration,
+            } => {
+                let deadline = match duration {
+                    Some(duration) => Some(
+                        Utc::now()
+                            .checked_add_signed(duration)
+                            .ok_or_else(|| PollError::InvalidDuration(format_duration(duration)))?,
+                    ),
+                    None => None,
+                };
+                let poll = Poll {
+                    room_id: ctx.room_id.clone(),
+                    creator: ctx.sender.clone(),
+                    question,
+                    options,
+                    votes: BTreeMap::new(),
+                    deadline,
+                    closed: false,
+                    native: self.config.native,
+                };
+
+                let event_id = if poll.native {
+                    context::room_send(&self.client, &ctx.room_id, poll.to_native_content()).await?
+                } else {
+                    let content = TextMessageEventContent::plain(poll.to_message());
+                    let keys = &OPTION_KEYS[..poll.options.len()];
+
+                    ctx.reply_with_buttons(plugin, MessageEventContent::Text(content), keys)
+                        .await?
+                };
+
+                self.storage.set(&poll_key(&event_id), &poll).await?;
+                self.storage
+                    .set(&latest_key(&ctx.room_id), &event_id)
+                    .await?;
+
+                if let Some(deadline) = poll.deadline {
+                    self.schedule_close(event_id, deadline);
+                }
+
+                Ok(None)
+            }
+            Command::Results => {
+                let (_, poll) = self.latest(&ctx.room_id).await?;
+
+                Ok(Some(poll.results()))
+            }
+            Command::Close => {
+                let (event_id, poll) = self.latest(&ctx.room_id).await?;
+
+                if poll.creator != ctx.sender {
+                    return Err(PollError::NotCreator);
+                }
+
+                if !self.close(&event_id).await? {
+                    return Err(PollError::AlreadyClosed);
+                }
+
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns the latest poll in `room_id`
+    async fn latest(&self, room_id: &RoomId) -> Result<(EventId, Poll), PollError> {
+        let event_id: EventId = self
+            .storage
+            .get(&latest_key(room_id))
+            .await?
+            .ok_or(PollError::NoPoll)?;
+        let poll = self
+            .storage
+            .get(&poll_key(&event_id))
+            .await?
+            .ok_or(PollError::NoPoll)?;
+
+        Ok((event_id, poll))
+    }
+
+    /// Records the vote of `user` in `room_id` for `option` in the poll `event_id`, if it's an
+    /// open poll in that room
+    async fn vote(&self, room_id: &RoomId, event_id: &EventId, user: &UserId, option: usize) {
+        let room_id = room_id.clone();
+        let user = user.to_string();
+        let result = self
+            .storage
+            .update(&poll_key(event_id), move |poll: Option<Poll>| {
+                let mut poll = poll?;
+
+                if poll.room_id == room_id && !poll.closed && option < poll.options.len() {
+                    poll.votes.insert(user, option);
+                }
+
+                Some(poll)
+            })
+            .await;
+
+        if let Err(err) = result {
+            error!("Failed to record vote in poll {}: {}", event_id, err);
+        }
+    }
+
+    /// Takes back the vote of `user` in `room_id` for `option` in the poll `event_id`, if it's an
+    /// open poll in that room and they haven't voted for another option since
+    async fn unvote(&self, room_id: &RoomId, event_id: &EventId, user: &UserId, option: usize) {
+        let room_id = room_id.clone();
+        let user = user.to_string();
+        let result = self
+            .storage
+            .update(&poll_key(event_id), move |poll: Option<Poll>| {
+                let mut poll = poll?;
+                let voted = poll.votes.get(&user) == Some(&option);
+
+                if poll.room_id == room_id && !poll.closed && voted {
+                    poll.votes.remove(&user);
+                }
+
+                Some(poll)
+            })
+            .await;
+
+        if let Err(err) = result {
+            error!("Failed to take back vote in poll {}: {}", event_id, err);
+        }
+    }
+
+    /// Closes the poll `event_id` and posts its results, returning whether it was still open
+    async fn close(&self, event_id: &EventId) -> Result<bool, PollError> {
+        let was_open = Arc::new(AtomicBool::new(false));
+        let closed_now = was_open.clone();
+        let poll = self
+            .storage
+            .update(&poll_key(event_id), move |poll: Option<Poll>| {
+                let mut poll = poll?;
+
+                closed_now.store(!poll.closed, Ordering::SeqCst);
+                poll.closed = true;
+
+                Some(poll)
+            })
+            .await?;
+
+        let poll = match poll {
+            Some(poll) if was_open.load(Ordering::SeqCst) => poll,
+            _ => return Ok(false),
+        };
+
+        debug!("Closed poll {}", event_id);
+
+        let content = if poll.native {
+            AnyMessageEventContent::Custom(CustomEventContent {
+                event_type: POLL_END_EVENT_TYPE.to_string(),
+                json: json!({
+                    "m.relates_to": { "rel_type": "m.reference", "event_id": event_id },
+                    POLL_END_EVENT_TYPE: {},
+                    "org.matrix.msc1767.text": poll.results(),
+                }),
+            })
+        } else {
+            AnyMessageEventContent::RoomMessage(MessageEventContent::Text(
+                TextMessageEventContent::plain(poll.results()),
+            ))
+        };
+
+        context::room_send(&self.client, &poll.room_id, content).await?;
+
+        Ok(true)
+    }
+
+    /// Closes the poll `event_id` once `deadline` has passed
+    fn schedule_close(self: &Arc<Self>, event_id: EventId, deadline: DateTime<Utc>) {
+        let inner = self.clone();
+
+        tokio::spawn(async move {
+            if let Ok(delay) = (deadline - Utc::now()).to_std() {
+                time::delay_for(delay).await;
+            }
+
+            if let Err(err) = inner.close(&event_id).await {
+                error!("Failed to close poll {}: {}", event_id, err);
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl Plugin for PollPlugin {
+    fn new(ctx: PluginContext) -> Result<Self, Error> {
+        let inner = Inner {
+            client: ctx.client,
+            storage: ctx.storage,
+            config: ctx.config.plugins.poll.clone(),
+        };
+
+        Ok(PollPlugin {
+            inner: Arc::new(inner),
+        })
+    }
+
+    fn ordered(&self) -> bool {
+        // A vote that is changed has to be counted after the vote it replaces
+        true
+    }
+
+    async fn start(&self) -> Result<(), Error> {
+        let polls: Vec<(String, Poll)> = self.inner.storage.list("poll/").await?;
+        let mut scheduled = 0;
+
+        for (key, poll) in polls {
+            let event_id = match EventId::try_from(&key["poll/".len()..]) {
+                Ok(event_id) => event_id,
+                Err(_) => continue,
+            };
+
+            if let (Some(deadline), false) = (poll.deadline, poll.closed) {
+                self.inner.schedule_close(event_id, deadline);
+                scheduled += 1;
+            }
+        }
+
+        debug!("Scheduled {} open polls to close", scheduled);
+
+        Ok(())
+    }
+
+    async fn on_room_text_message(&self, ctx: &MessageContext, message: &TextMessageEventContent) {
+        // Editing a request shouldn't start another poll
+        if ctx.is_edit() {
+            return;
+        }
+
+        let command = match parse_command(&message.body) {
+            Some(command) => command,
+            None => return,
+        };
+
+        let result = match command {
+            Ok(command) => match self.inner.handle_command(self, ctx, command).await {
+                Ok(reply) => {
+                    ctx.command_succeeded();
+
+                    // When there's no reply, the poll itself is the reply
+                    match reply {
+                        Some(reply) => ctx.reply_text(reply).await.map(|_| ()),
+                        None => Ok(()),
+                    }
+                }
+                Err(err) => ctx.reply_error(err).await,
+            },
+            Err(err) => ctx.reply_error(err).await,
+        };
+
+        if let Err(err) = result {
+            error!("Failed to send reply: {}", err);
+        }
+    }
+
+    async fn on_room_custom_event(&self, ctx: &MessageContext, content: &CustomEventContent) {
+        if let Some((event_id, option)) = parse_response(content) {
+            self.inner
+                .vote(&ctx.room_id, &event_id, &ctx.sender, option)
+                .await;
+        }
+    }
+
+    async fn on_button(&self, ctx: &MessageContext, reaction: &Reaction) {
+        if let Some(option) = OPTION_KEYS.iter().position(|key| *key == reaction.key) {
+            self.inner
+                .vote(&ctx.room_id, &reaction.event_id, &ctx.sender, option)
+                .await;
+        }
+    }
+
+    async fn on_button_released(&self, ctx: &MessageContext, reaction: &Reaction) {
+        if let Some(option) = OPTION_KEYS.iter().position(|key| *key == reaction.key) {
+            self.inner
+                .unvote(&ctx.room_id, &reaction.event_id, &ctx.sender, option)
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn poll(votes: &[(&str, usize)]) -> Poll {
+        Poll {
+            room_id: RoomId::try_from("!room:example.com").unwrap(),
+            creator: UserId::try_from("@alice:example.com").unwrap(),
+            question: "Lunch?".to_string(),
+            options: vec!["pizza".to_string(), "sushi".to_string()],
+            votes: votes
+                .iter()
+                .map(|(user, option)| (user.to_string(), *option))
+                .collect(),
+            deadline: None,
+            closed: false,
+            native: false,
+        }
+    }
+
+    #[test]
+    fn test_parse_start() {
+        let command = parse_command(r#".poll "Lunch?" pizza, sushi , burger"#).unwrap();
+
+        assert_eq!(
+            command.unwrap(),
+            Command::Start {
+                question: "Lunch?".to_string(),
+                options: vec![
+                    "pizza".to_string(),
+                    "sushi".to_string(),
+                    "burger".to_string()
+                ],
+                duration: None,
+            }
+        );
+
+        match parse_command(r#".poll 30m "Lunch?" pizza, sushi"#).unwrap() {
+            Ok(Command::Start { duration, .. }) => {
+                assert_eq!(duration, Some(Duration::minutes(30)))
+            }
+            command => panic!("unexpected command: {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse_command(".polls").is_none());
+        assert!(parse_command("what about a .poll").is_none());
+        assert_eq!(
+            parse_command(".poll results").unwrap().unwrap(),
+            Command::Results
+        );
+        assert!(matches!(
+            parse_command(r#".poll "Lunch?" pizza"#),
+            Some(Err(PollError::OptionCount))
+        ));
+        assert!(matches!(
+            parse_command(r#".poll 0m "Lunch?" pizza, sushi"#),
+            Some(Err(PollError::InvalidDuration(_)))
+        ));
+        assert!(matches!(
+            parse_command(r#".poll 99999999999999w "Lunch?" pizza, sushi"#),
+            Some(Err(PollError::InvalidDuration(_)))
+        ));
+        assert!(matches!(
+            parse_command(r#".poll soon "Lunch?" pizza, sushi"#),
+            Some(Err(PollError::Usage))
+        ));
+        assert!(matches!(
+            parse_command(".poll Lunch? pizza, sushi"),
+            Some(Err(PollError::Usage))
+        ));
+        assert!(matches!(
+            parse_command(".poll"),
+            Some(Err(PollError::Usage))
+        ));
+    }
+
+    #[test]
+    fn test_results() {
+        let mut poll = poll(&[
+            ("@alice:example.com", 0),
+            ("@bob:example.com", 1),
+            ("@carol:example.com", 0),
+        ]);
+
+        assert_eq!(poll.tally(), vec![2, 1]);
+        assert_eq!(
+            poll.results(),
+            "Lunch? (3 votes)\n1. pizza: 2 (66%)\n2. sushi: 1 (33%)"
+        );
+
+        poll.closed = true;
+        poll.votes.clear();
+        assert_eq!(
+            poll.results(),
+            "Lunch? (0 votes, closed)\n1. pizza: 0\n2. sushi: 0"
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let content = CustomEventContent {
+            event_type: "org.matrix.msc3381.poll.response".to_string(),
+            json: json!({
+                "m.relates_to": { "rel_type": "m.reference", "event_id": "$poll:example.com" },
+                "org.matrix.msc3381.poll.response": { "answers": ["2"] },
+            }),
+        };
+
+        assert_eq!(
+            parse_response(&content),
+            Some((EventId::try_from("$poll:example.com").unwrap(), 1))
+        );
+
+        let stable = CustomEventContent {
+            event_type: "m.poll.response".to_string(),
+            json: json!({
+                "m.relates_to": { "rel_type": "m.reference", "event_id": "$poll:example.com" },
+                "m.selections": ["0"],
+            }),
+        };
+
+        assert_eq!(parse_response(&stable), None);
+    }
+}
@@ -0,0 +1,511 @@
+//! A database of memorable quotes, kept per room
+//!
+//! Usage:
+//!
+//! ```text
+//! .quote
+//! .quote <id>
+//! .quote add <text>
+//! .quote search <term>
+//! .quote del <id>
+//! .quote export
+//! .quote import [room id]
+//! ```
+//!
+//! `.quote add` without any text, in reply to a message, quotes that message. Text that starts
+//! with `<nick>` is attributed to `nick`.
+//!
+//! Only admins can delete, export and import quotes. The quotes of each room are exported to a
+//! JSON file of their own in the data directory. Importing reads the file of the room, or the file
+//! of another room if its id is given, so quotes can be moved to another room or bot.
+
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::error;
+use matrix_sdk::events::room::message::TextMessageEventContent;
+use matrix_sdk_common::identifiers::{RoomId, UserId};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::context::{strip_reply_fallback, MessageContext};
+use crate::history::{self, History, HistoryMessage};
+use crate::plugin::{Plugin, PluginContext};
+use crate::storage::{Storage, StorageError};
+use crate::Error;
+
+/// The maximum number of quotes to list in reply to a search
+const MAX_SEARCH_RESULTS: usize = 5;
+
+#[derive(thiserror::Error, Debug)]
+pub enum QuoteError {
+    #[error("usage: .quote [<id>|add <text>|search <term>|del <id>|export|import [room id]]")]
+    Usage,
+    #[error("usage: .quote add <text>, or reply to a message with .quote add")]
+    NothingToQuote,
+    #[error("only text messages can be quoted")]
+    NotText,
+    #[error("there is no quote #{0}")]
+    NotFound(u64),
+    #[error("there are no quotes yet")]
+    NoQuotes,
+    #[error("only admins can do that")]
+    NotAdmin,
+    #[error("unable to read or write the quotes file: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("invalid quotes file: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("unable to read or write quotes")]
+    StorageError(#[from] StorageError),
+    #[error("matrix error")]
+    MatrixError(#[from] matrix_sdk::Error),
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Random,
+    Show(u64),
+    /// Adds the text, or the message that the command replies to if it's empty
+    Add(String),
+    Search(String),
+    Delete(u64),
+    Export,
+    /// Imports the quotes exported from the given room, or from this room
+    Import(Option<RoomId>),
+}
+
+/// A single stored quote
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Quote {
+    id: u64,
+    text: String,
+    /// The user id or nick of whoever said it, if known
+    author: Option<String>,
+    added_by: UserId,
+    added_at: DateTime<Utc>,
+}
+
+pub struct QuotesPlugin {
+    storage: Storage,
+    history: Arc<History>,
+    admins: Vec<String>,
+    /// The directory that quotes are exported to and imported from
+    export_dir: PathBuf,
+}
+
+/// Parses the body of a text message as a command
+///
+/// Returns `None` if the message isn't a quote command at all
+fn parse_command(body: &str) -> Option<Result<Command, QuoteError>> {
+    let rest = body.trim().strip_prefix(".quote")?;
+
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let rest = rest.trim();
+    let (subcommand, argument) = match rest.find(char::is_whitespace) {
+        Some(end) => (&rest[..end], rest[end..].trim()),
+        None => (rest, ""),
+    };
+    let parse_id = |id: &str| id.trim_start_matches('#').parse().ok();
+
+    let command = match (subcommand, argument) {
+        ("", _) => Some(Command::Random),
+        ("add", text) => Some(Command::Add(text.to_string())),
+        ("search", term) if !term.is_empty() => Some(Command::Search(term.to_string())),
+        ("del", id) => parse_id(id).map(Command::Delete),
+        ("export", "") => Some(Command::Export),
+        ("import", "") => Some(Command::Import(None)),
+        ("import", room_id) => RoomId::try_from(room_id)
+            .ok()
+            .map(|room_id| Command::Import(Some(room_id))),
+        (id, "") => parse_id(id).map(Command::Show),
+        _ => None,
+    };
+
+    Some(command.ok_or(QuoteError::Usage))
+}
+
+/// Splits an IRC-style `<nick> text` quote into its author and text
+fn parse_author(text: &str) -> (Option<String>, String) {
+    if let Some(rest) = text.strip_prefix('<') {
+        if let Some(end) = rest.find('>') {
+            let author = rest[..end].trim();
+            let text = rest[end + 1..].trim();
+
+            if !author.is_empty() && !text.is_empty() {
+                return (Some(author.to_string()), text.to_string());
+            }
+        }
+    }
+
+    (None, text.to_string())
+}
+
+/// Returns the name of the file that the quotes of `room_id` are exported to
+///
+/// Anything but ASCII letters, digits, `.` and `-` is escaped, so room ids can't point outside
+/// the data directory and different rooms can't share a file.
+fn export_file_name(room_id: &RoomId) -> String {
+    let mut name = String::from("quotes-");
+
+    for byte in room_id.as_str().bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' => name.push(byte as char),
+            _ => name.push_str(&format!("_{:02x}", byte)),
+        }
+    }
+
+    name.push_str(".json");
+    name
+}
+
+/// Returns the storage key of the quote `id` in `room_id`
+fn quote_key(room_id: &RoomId, id: u64) -> String {
+    format!("{}/quote/{}", room_id, id)
+}
+
+impl Quote {
+    /// Returns the quote as it's shown in rooms
+    fn describe(&self) -> String {
+        let author = match self.author {
+            // Show user ids like people are addressed in most clients
+            Some(ref author) => match UserId::try_from(author.as_str()) {
+                Ok(user_id) => format!(" — {}", user_id.localpart()),
+                Err(_) => format!(" — {}", author),
+            },
+            None => String::new(),
+        };
+
+        format!(
+            "#{}: {}{} ({})",
+            self.id,
+            self.text,
+            author,
+            self.added_at.format("%Y-%m-%d")
+        )
+    }
+
+    /// Returns whether the text or the author of the quote contains `term`, ignoring case
+    fn matches(&self, term: &str) -> bool {
+        let term = term.to_lowercase();
+
+        self.text.to_lowercase().contains(&term)
+            || self
+                .author
+                .as_ref()
+                .map_or(false, |author| author.to_lowercase().contains(&term))
+    }
+}
+
+impl QuotesPlugin {
+    /// Returns whether `user` is allowed to delete, export and import quotes
+    fn is_admin(&self, user: &UserId) -> bool {
+        self.admins.iter().any(|admin| admin == user.as_str())
+    }
+
+    /// Returns all the quotes in `room_id`, ordered by id
+    async fn quotes(&self, room_id: &RoomId) -> Result<Vec<Quote>, QuoteError> {
+        let mut quotes: Vec<Quote> = self
+            .storage
+            .list(&format!("{}/quote/", room_id))
+            .await?
+            .into_iter()
+            .map(|(_, quote)| quote)
+            .collect();
+
+        quotes.sort_by_key(|quote| quote.id);
+
+        Ok(quotes)
+    }
+
+    /// Stores `quote` in `room_id` with the next free id and returns that id
+    async fn add(&self, room_id: &RoomId, mut quote: Quote) -> Result<u64, QuoteError> {
+        quote.id = self
+            .storage
+            .update(&format!("{}/next_id", room_id), |id: Option<u64>| {
+                Some(id.unwrap_or(0) + 1)
+            })
+            .await?
+            .unwrap_or_default();
+
+        self.storage
+            .set(&quote_key(room_id, quote.id), &quote)
+            .await?;
+
+        Ok(quote.id)
+    }
+
+    /// Returns the message that the command in `ctx` replies to
+    async fn replied_message(&self, ctx: &MessageContext) -> Result<HistoryMessage, QuoteError> {
+        let event_id = ctx.in_reply_to().ok_or(QuoteError::NothingToQuote)?;
+
+        // Recent messages are already decrypted, so only look up older ones
+        if let Some(message) = self.history.get(&ctx.room_id, event_id) {
+            return Ok(message);
+        }
+
+        let event = ctx.room_event(event_id).await?;
+
+        history::parse_message(&event).ok_or(QuoteError::NotText)
+    }
+
+    async fn handle_command(
+        &self,
+        ctx: &MessageContext,
+        command: Command,
+    ) -> Result<String, QuoteError> {
+        let room_id = &ctx.room_id;
+
+        match command {
+            Command::Random => {
+                let quotes = self.quotes(room_id).await?;
+                let quote = quotes
+                    .choose(&mut rand::thread_rng())
+                    .ok_or(QuoteError::NoQuotes)?;
+
+                Ok(quote.describe())
+            }
+            Command::Show(id) => {
+                let quote: Quote = self
+                    .storage
+                    .get(&quote_key(room_id, id))
+                    .await?
+                    .ok_or(QuoteError::NotFound(id))?;
+
+                Ok(quote.describe())
+            }
+            Command::Add(text) => {
+                let (author, text) = if text.is_empty() {
+                    let message = self.replied_message(ctx).await?;
+
+                    (Some(message.sender.to_string()), message.body)
+                } else {
+                    parse_author(&text)
+                };
+                let quote = Quote {
+                    id: 0,
+                    text,
+                    author,
+                    added_by: ctx.sender.clone(),
+                    added_at: Utc::now(),
+                };
+                let id = self.add(room_id, quote).await?;
+
+                Ok(format!("Added quote #{}", id))
+            }
+            Command::Search(term) => {
+                let quotes = self.quotes(room_id).await?;
+                let found: Vec<&Quote> =
+                    quotes.iter().filter(|quote| quote.matches(&term)).collect();
+
+                if found.is_empty() {
+                    return Ok(format!("No quotes match `{}'", term));
+                }
+
+                // The newest quotes first
+                let mut lines: Vec<String> = found
+                    .iter()
+                    .rev()
+                    .take(MAX_SEARCH_RESULTS)
+                    .map(|quote| quote.describe())
+                    .collect();
+
+                if found.len() > MAX_SEARCH_RESULTS {
+                    lines.push(format!("… and {} more", found.len() - MAX_SEARCH_RESULTS));
+                }
+
+                Ok(lines.join("\n"))
+            }
+            Command::Delete(id) => {
+                if !self.is_admin(&ctx.sender) {
+                    return Err(QuoteError::NotAdmin);
+                }
+
+                if !self.storage.delete(&quote_key(room_id, id)).await? {
+                    return Err(QuoteError::NotFound(id));
+                }
+
+                Ok(format!("Deleted quote #{}", id))
+            }
+            Command::Export => {
+                if !self.is_admin(&ctx.sender) {
+                    return Err(QuoteError::NotAdmin);
+                }
+
+                let quotes = self.quotes(room_id).await?;
+                let file_name = export_file_name(room_id);
+
+                fs::write(
+                    self.export_dir.join(&file_name),
+                    serde_json::to_vec_pretty(&quotes)?,
+                )
+                .await?;
+
+                Ok(format!("Exported {} quotes to {}", quotes.len(), file_name))
+            }
+            Command::Import(source) => {
+                if !self.is_admin(&ctx.sender) {
+                    return Err(QuoteError::NotAdmin);
+                }
+
+                let file_name = export_file_name(source.as_ref().unwrap_or(room_id));
+                let imported: Vec<Quote> =
+                    serde_json::from_slice(&fs::read(self.export_dir.join(&file_name)).await?)?;
+                let existing = self.quotes(room_id).await?;
+                let mut added = 0;
+
+                // Quotes get new ids, so importing the same file twice must not add them twice
+                for quote in imported {
+                    if existing.iter().any(|other| other.text == quote.text) {
+                        continue;
+                    }
+
+                    self.add(room_id, quote).await?;
+                    added += 1;
+                }
+
+                Ok(format!("Imported {} quotes from {}", added, file_name))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Plugin for QuotesPlugin {
+    fn new(ctx: PluginContext) -> Result<Self, Error> {
+        Ok(QuotesPlugin {
+            storage: ctx.storage,
+            history: ctx.history,
+            admins: ctx.config.matrix.admins.clone(),
+            export_dir: ctx.config.data_dir.clone(),
+        })
+    }
+
+    async fn on_room_text_message(&self, ctx: &MessageContext, message: &TextMessageEventContent) {
+        if ctx.is_edit() {
+            return;
+        }
+
+        // Quoting a message means replying to it, so the command comes after the reply fallback
+        let result = match parse_command(strip_reply_fallback(&message.body)) {
+            Some(Ok(command)) => {
+                ctx.reply_result(self.handle_command(ctx, command).await)
+                    .await
+            }
+            Some(Err(err)) => ctx.reply_error(err).await,
+            None => return,
+        };
+
+        if let Err(err) = result {
+            error!("Failed to send reply: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command(".quote").unwrap().unwrap(), Command::Random);
+        assert_eq!(
+            parse_command(".quote #12").unwrap().unwrap(),
+            Command::Show(12)
+        );
+        assert_eq!(
+            parse_command(".quote add <alice> it works on my machine")
+                .unwrap()
+                .unwrap(),
+            Command::Add("<alice> it works on my machine".to_string())
+        );
+        assert_eq!(
+            parse_command(".quote add").unwrap().unwrap(),
+            Command::Add(String::new())
+        );
+        assert_eq!(
+            parse_command(".quote search  my machine").unwrap().unwrap(),
+            Command::Search("my machine".to_string())
+        );
+        assert_eq!(
+            parse_command(".quote del 3").unwrap().unwrap(),
+            Command::Delete(3)
+        );
+        assert_eq!(
+            parse_command(".quote export").unwrap().unwrap(),
+            Command::Export
+        );
+        assert_eq!(
+            parse_command(".quote import").unwrap().unwrap(),
+            Command::Import(None)
+        );
+        assert_eq!(
+            parse_command(".quote import !other:example.com")
+                .unwrap()
+                .unwrap(),
+            Command::Import(Some(RoomId::try_from("!other:example.com").unwrap()))
+        );
+        assert!(matches!(
+            parse_command(".quote import ../secrets"),
+            Some(Err(QuoteError::Usage))
+        ));
+        assert!(matches!(
+            parse_command(".quote del everything"),
+            Some(Err(QuoteError::Usage))
+        ));
+        assert!(matches!(
+            parse_command(".quote search"),
+            Some(Err(QuoteError::Usage))
+        ));
+        assert!(parse_command(".quotes").is_none());
+        assert!(parse_command("a .quote").is_none());
+    }
+
+    #[test]
+    fn test_export_file_name() {
+        let room_id = RoomId::try_from("!abc_D-1:example.com").unwrap();
+
+        assert_eq!(
+            export_file_name(&room_id),
+            "quotes-_21abc_5fD-1_3aexample.com.json"
+        );
+    }
+
+    #[test]
+    fn test_parse_author() {
+        assert_eq!(
+            parse_author("<alice> it works on my machine"),
+            (
+                Some("alice".to_string()),
+                "it works on my machine".to_string()
+            )
+        );
+        assert_eq!(parse_author("<3 rust"), (None, "<3 rust".to_string()));
+        assert_eq!(parse_author("no author"), (None, "no author".to_string()));
+    }
+
+    #[test]
+    fn test_describe() {
+        let quote = Quote {
+            id: 7,
+            text: "it works on my machine".to_string(),
+            author: Some("@alice:example.com".to_string()),
+            added_by: UserId::try_from("@bob:example.com").unwrap(),
+            added_at: "2020-10-18T12:00:00Z".parse().unwrap(),
+        };
+
+        assert_eq!(
+            quote.describe(),
+            "#7: it works on my machine — alice (2020-10-18)"
+        );
+        assert!(quote.matches("MACHINE"));
+        assert!(quote.matches("alice"));
+        assert!(!quote.matches("bob"));
+    }
+}
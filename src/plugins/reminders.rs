@@ -0,0 +1,727 @@
+//! Reminders and scheduled messages
+//!
+//! Understands commands such as:
+//!
+//! ```text
+//! .remind me in 2h to deploy
+//! .remind #room:example.com at 09:00 tomorrow standup
+//! .remind me every 1d at 09:00 to water the plants
+//! .remind list
+//! .remind cancel 3
+//! ```
+//!
+//! Reminders are kept in the plugin storage so they survive restarts.
+
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Local, NaiveTime, TimeZone, Utc};
+use log::{debug, error};
+use matrix_sdk::{
+    api::r0::alias::get_alias,
+    events::{
+        room::message::{MessageEventContent, TextMessageEventContent},
+        AnyMessageEventContent,
+    },
+    Client,
+};
+use matrix_sdk_common::identifiers::{RoomAliasId, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
+use tokio::time;
+
+use crate::context::{self, MessageContext};
+use crate::duration::{self, format_duration};
+use crate::plugin::{Plugin, PluginContext};
+use crate::storage::{Storage, StorageError};
+use crate::Error;
+
+/// The shortest interval allowed between two occurrences of a recurring reminder
+const MIN_REPEAT_SECS: i64 = 60;
+
+/// The longest time to sleep when there are no pending reminders
+const IDLE_DELAY: StdDuration = StdDuration::from_secs(3600);
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReminderError {
+    #[error("usage: .remind <me|#room> <in <duration>|at <HH:MM> [today|tomorrow]|every <duration> [at <HH:MM>]> [to] <message>")]
+    Usage,
+    #[error("invalid duration `{0}'")]
+    InvalidDuration(String),
+    #[error("invalid time `{0}'")]
+    InvalidTime(String),
+    #[error("recurring reminders must be at least a minute apart")]
+    RepeatTooShort,
+    #[error("you have no reminder with id {0}")]
+    NotFound(u64),
+    #[error("could not resolve room `{0}'")]
+    UnknownRoom(String),
+    #[error("you can only set reminders in rooms that you have joined")]
+    NotMember,
+    #[error("unable to read or write reminders")]
+    StorageError(#[from] StorageError),
+    #[error("matrix error")]
+    MatrixError(#[from] matrix_sdk::Error),
+}
+
+/// The room a reminder is requested for
+#[derive(Debug, PartialEq)]
+enum Target {
+    /// The room the request was made in
+    Me,
+    /// A room alias or room id
+    Room(String),
+}
+
+/// When a reminder is due, and optionally how often it repeats
+#[derive(Debug, PartialEq)]
+struct Schedule {
+    due: DateTime<Utc>,
+    repeat: Option<Duration>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Add {
+        target: Target,
+        schedule: Schedule,
+        message: String,
+    },
+    List,
+    Cancel(u64),
+}
+
+/// A single stored reminder
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Reminder {
+    id: u64,
+    user_id: UserId,
+    room_id: RoomId,
+    message: String,
+    due: DateTime<Utc>,
+    /// The number of seconds between each occurrence, if recurring
+    repeat_secs: Option<i64>,
+}
+
+struct Inner {
+    client: Client,
+    storage: Storage,
+    /// All pending reminders, mirroring what's in storage
+    reminders: Mutex<Vec<Reminder>>,
+    /// Notified whenever the set of reminders changes so the scheduler can wake up
+    changed: Notify,
+}
+
+pub struct RemindersPlugin {
+    inner: Arc<Inner>,
+}
+
+/// Parses a duration such as `2h`, `1h30m` or `1w`
+fn parse_duration(s: &str) -> Result<Duration, ReminderError> {
+    duration::parse_duration(s).ok_or_else(|| ReminderError::InvalidDuration(s.to_string()))
+}
+
+/// Resolves `HH:MM` and an optional day to a point in time relative to `now`
+///
+/// If no day is given and the time has already passed today, the time tomorrow is used instead
+fn parse_time_of_day<Tz: TimeZone>(
+    time: &str,
+    day: Option<&str>,
+    now: &DateTime<Tz>,
+) -> Result<DateTime<Utc>, ReminderError> {
+    let time = NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|_| ReminderError::InvalidTime(time.to_string()))?;
+    let today = now.date();
+    let date = match day {
+        Some("today") => today,
+        Some("tomorrow") => today.succ(),
+        Some(_) => return Err(ReminderError::Usage),
+        None => match today.and_time(time) {
+            Some(ref datetime) if datetime > now => today,
+            _ => today.succ(),
+        },
+    };
+
+    date.and_time(time)
+        .map(|datetime| datetime.with_timezone(&Utc))
+        .ok_or_else(|| ReminderError::InvalidTime(time.to_string()))
+}
+
+/// Returns `duration` after `now`, or an error naming `input` if that is too far in the future
+fn add_duration<Tz: TimeZone>(
+    now: &DateTime<Tz>,
+    duration: Duration,
+    input: &str,
+) -> Result<DateTime<Utc>, ReminderError> {
+    now.with_timezone(&Utc)
+        .checked_add_signed(duration)
+        .ok_or_else(|| ReminderError::InvalidDuration(input.to_string()))
+}
+
+/// Returns the first occurrence of a recurring reminder after `now`, or `None` if it would be
+/// too far in the future to represent
+fn next_occurrence(
+    mut due: DateTime<Utc>,
+    repeat_secs: i64,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    // Skip any occurrences that were missed while we were offline
+    while due <= now {
+        due = due.checked_add_signed(Duration::seconds(repeat_secs))?;
+    }
+
+    Some(due)
+}
+
+/// Parses the body of a text message as a reminder command
+///
+/// Returns `None` if the message isn't a reminder command at all
+fn parse_command<Tz: TimeZone>(
+    body: &str,
+    now: DateTime<Tz>,
+) -> Option<Result<Command, ReminderError>> {
+    let mut words = body.split_whitespace();
+
+    match words.next() {
+        Some(".reminders") => return Some(Ok(Command::List)),
+        Some(".remind") => {}
+        _ => return None,
+    }
+
+    let words: Vec<&str> = words.collect();
+
+    Some(parse_arguments(&words, now))
+}
+
+fn parse_arguments<Tz: TimeZone>(
+    words: &[&str],
+    now: DateTime<Tz>,
+) -> Result<Command, ReminderError> {
+    let target = match words.first() {
+        Some(&"list") if words.len() == 1 => return Ok(Command::List),
+        Some(&"cancel") if words.len() == 2 => {
+            let id = words[1].trim_start_matches('#');
+
+            return id
+                .parse()
+                .map(Command::Cancel)
+                .map_err(|_| ReminderError::Usage);
+        }
+        Some(&"me") => Target::Me,
+        Some(room) if room.starts_with('#') || room.starts_with('!') => {
+            Target::Room(room.to_string())
+        }
+        _ => return Err(ReminderError::Usage),
+    };
+
+    let mut rest = &words[1..];
+    let args = rest;
+    let schedule = match args {
+        ["in", duration, ..] => {
+            rest = &rest[2..];
+
+            Schedule {
+                due: add_duration(&now, parse_duration(duration)?, duration)?,
+                repeat: None,
+            }
+        }
+        ["at", time, day @ "today", ..] | ["at", time, day @ "tomorrow", ..] => {
+            rest = &rest[3..];
+
+            Schedule {
+                due: parse_time_of_day(time, Some(*day), &now)?,
+                repeat: None,
+            }
+        }
+        ["at", time, ..] => {
+            rest = &rest[2..];
+
+            Schedule {
+                due: parse_time_of_day(time, None, &now)?,
+                repeat: None,
+            }
+        }
+        ["every", interval_str, ..] => {
+            let interval = parse_duration(interval_str)?;
+
+            if interval.num_seconds() < MIN_REPEAT_SECS {
+                return Err(ReminderError::RepeatTooShort);
+            }
+
+            rest = &rest[2..];
+
+            let args = rest;
+            let due = match args {
+                ["at", time, day @ "today", ..] | ["at", time, day @ "tomorrow", ..] => {
+                    rest = &rest[3..];
+                    parse_time_of_day(time, Some(*day), &now)?
+                }
+                ["at", time, ..] => {
+                    rest = &rest[2..];
+                    parse_time_of_day(time, None, &now)?
+                }
+                _ => add_duration(&now, interval, interval_str)?,
+            };
+
+            Schedule {
+                due,
+                repeat: Some(interval),
+            }
+        }
+        _ => return Err(ReminderError::Usage),
+    };
+
+    if let ["to", ..] = rest {
+        rest = &rest[1..];
+    }
+
+    if rest.is_empty() {
+        return Err(ReminderError::Usage);
+    }
+
+    Ok(Command::Add {
+        target,
+        schedule,
+        message: rest.join(" "),
+    })
+}
+
+impl Reminder {
+    /// Returns the storage key of the reminder
+    fn key(&self) -> String {
+        format!("reminder/{}", self.id)
+    }
+
+    /// Returns a one-line description of the reminder for listings
+    fn describe(&self) -> String {
+        let due = self.due.with_timezone(&Local).format("%Y-%m-%d %H:%M");
+
+        match self.repeat_secs {
+            Some(secs) => format!(
+                "#{} in {} at {} (every {}): {}",
+                self.id,
+                self.room_id,
+                due,
+                format_duration(Duration::seconds(secs)),
+                self.message
+            ),
+            None => format!(
+                "#{} in {} at {}: {}",
+                self.id, self.room_id, due, self.message
+            ),
+        }
+    }
+}
+
+impl Inner {
+    /// Sends a text message with an html body to the given room
+    async fn send(&self, room: &RoomId, plain: String, html: String) -> Result<(), ReminderError> {
+        let content = AnyMessageEventContent::RoomMessage(MessageEventContent::Text(
+            TextMessageEventContent::html(plain, html),
+        ));
+
+        context::room_send(&self.client, room, content).await?;
+
+        Ok(())
+    }
+
+    /// Resolves a reminder target to a room id
+    async fn resolve_target(
+        &self,
+        target: Target,
+        user: &UserId,
+        room: &RoomId,
+    ) -> Result<RoomId, ReminderError> {
+        let name = match target {
+            Target::Me => return Ok(room.clone()),
+            Target::Room(name) => name,
+        };
+
+        let room_id = self.resolve_room(name, user).await?;
+
+        // Otherwise anyone could make us post into any room that we're in
+        if !self.is_joined_member(&room_id, user).await {
+            return Err(ReminderError::NotMember);
+        }
+
+        Ok(room_id)
+    }
+
+    /// Returns whether `user` has joined `room_id`, as far as we can tell from our own membership
+    async fn is_joined_member(&self, room_id: &RoomId, user: &UserId) -> bool {
+        match self.client.get_joined_room(room_id).await {
+            Some(room) => room.read().await.joined_members.contains_key(user),
+            None => false,
+        }
+    }
+
+    /// Resolves a room alias or room id to a room id
+    async fn resolve_room(&self, name: String, user: &UserId) -> Result<RoomId, ReminderError> {
+        if name.starts_with('!') {
+            return RoomId::try_from(name.as_str()).map_err(|_| ReminderError::UnknownRoom(name));
+        }
+
+        // Allow `#room` as a shorthand for a room on the requesting user's homeserver
+        let alias = if name.contains(':') {
+            name.clone()
+        } else {
+            format!("{}:{}", name, user.server_name())
+        };
+        let alias = RoomAliasId::try_from(alias.as_str())
+            .map_err(|_| ReminderError::UnknownRoom(name.clone()))?;
+        let response = self
+            .client
+            .send(get_alias::Request::new(&alias))
+            .await
+            .map_err(|_| ReminderError::UnknownRoom(name))?;
+
+        Ok(response.room_id)
+    }
+
+    async fn handle_command(
+        &self,
+        command: Command,
+        user: &UserId,
+        room: &RoomId,
+    ) -> Result<String, ReminderError> {
+        match command {
+            Command::Add {
+                target,
+                schedule,
+                message,
+            } => {
+                let room_id = self.resolve_target(target, user, room).await?;
+                let id = self
+                    .storage
+                    .update("next_id", |id: Option<u64>| Some(id.unwrap_or(0) + 1))
+                    .await?
+                    .unwrap_or_default();
+                let reminder = Reminder {
+                    id,
+                    user_id: user.clone(),
+                    room_id,
+                    message,
+                    due: schedule.due,
+                    repeat_secs: schedule.repeat.map(|repeat| repeat.num_seconds()),
+                };
+                let reply = format!("Okay, I'll remind you: {}", reminder.describe());
+
+                self.storage.set(&reminder.key(), &reminder).await?;
+                self.reminders.lock().await.push(reminder);
+                self.changed.notify();
+
+                Ok(reply)
+            }
+            Command::List => {
+                let reminders = self.reminders.lock().await;
+                let reminders: Vec<String> = reminders
+                    .iter()
+                    .filter(|reminder| &reminder.user_id == user)
+                    .map(Reminder::describe)
+                    .collect();
+
+                if reminders.is_empty() {
+                    Ok("You have no reminders".to_string())
+                } else {
+                    Ok(reminders.join("\n"))
+                }
+            }
+            Command::Cancel(id) => {
+                let mut reminders = self.reminders.lock().await;
+                let position = reminders
+                    .iter()
+                    .position(|reminder| reminder.id == id && &reminder.user_id == user)
+                    .ok_or(ReminderError::NotFound(id))?;
+
+                self.storage.delete(&reminders[position].key()).await?;
+                reminders.remove(position);
+                self.changed.notify();
+
+                Ok(format!("Cancelled reminder #{}", id))
+            }
+        }
+    }
+
+    /// Removes and returns all due reminders, rescheduling the recurring ones
+    ///
+    /// Also returns the time at which the next reminder is due, if any
+    async fn take_due(&self) -> (Vec<Reminder>, Option<DateTime<Utc>>) {
+        let now = Utc::now();
+        let mut reminders = self.reminders.lock().await;
+        let mut due = vec![];
+
+        for reminder in reminders.iter_mut().filter(|r| r.due <= now) {
+            due.push(reminder.clone());
+
+            let next_due = reminder
+                .repeat_secs
+                .and_then(|secs| next_occurrence(reminder.due, secs, now));
+            let result = match next_due {
+                Some(next_due) => {
+                    reminder.due = next_due;
+
+                    self.storage.set(&reminder.key(), &*reminder).await
+                }
+                None => self.storage.delete(&reminder.key()).await.map(|_| ()),
+            };
+
+            if let Err(err) = result {
+                error!("Failed to save reminder #{}: {}", reminder.id, err);
+            }
+        }
+
+        reminders.retain(|reminder| reminder.due > now);
+
+        let next_due = reminders.iter().map(|reminder| reminder.due).min();
+
+        (due, next_due)
+    }
+
+    /// Delivers a reminder to its room, mentioning the user that requested it
+    async fn deliver(&self, reminder: &Reminder) -> Result<(), ReminderError> {
+        debug!(
+            "Delivering reminder #{} to {}",
+            reminder.id, reminder.room_id
+        );
+
+        let plain = format!(
+            "{}: reminder: {}",
+            reminder.user_id.localpart(),
+            reminder.message
+        );
+        let html = format!(
+            "<a href=\"https://matrix.to/#/{}\">{}</a>: reminder: {}",
+            reminder.user_id.as_str(),
+            reminder.user_id.localpart(),
+            reminder.message
+        );
+
+        self.send(&reminder.room_id, plain, html).await
+    }
+
+    /// Delivers reminders as they become due, forever
+    async fn run_scheduler(self: Arc<Self>) {
+        loop {
+            let (due, next_due) = self.take_due().await;
+
+            for reminder in due.iter() {
+                if let Err(err) = self.deliver(reminder).await {
+                    error!("Failed to deliver reminder #{}: {}", reminder.id, err);
+                }
+            }
+
+            let delay = next_due
+                .and_then(|next_due| (next_due - Utc::now()).to_std().ok())
+                .unwrap_or(IDLE_DELAY)
+                .min(IDLE_DELAY);
+
+            tokio::select! {
+                _ = time::delay_for(delay) => {}
+                _ = self.changed.notified() => {}
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Plugin for RemindersPlugin {
+    fn new(ctx: PluginContext) -> Result<Self, Error> {
+        let inner = Inner {
+            client: ctx.client,
+            storage: ctx.storage,
+            reminders: Mutex::new(vec![]),
+            changed: Notify::new(),
+        };
+
+        Ok(RemindersPlugin {
+            inner: Arc::new(inner),
+        })
+    }
+
+    async fn start(&self) -> Result<(), Error> {
+        let reminders: Vec<Reminder> = self
+            .inner
+            .storage
+            .list("reminder/")
+            .await?
+            .into_iter()
+            .map(|(_, reminder)| reminder)
+            .collect();
+
+        debug!("Loaded {} reminders", reminders.len());
+
+        *self.inner.reminders.lock().await = reminders;
+
+        tokio::spawn(self.inner.clone().run_scheduler());
+
+        Ok(())
+    }
+
+    async fn on_room_text_message(&self, ctx: &MessageContext, message: &TextMessageEventContent) {
+        // Editing a request shouldn't create another reminder
+        if ctx.is_edit() {
+            return;
+        }
+
+        let command = match parse_command(&message.body, Local::now()) {
+            Some(command) => command,
+            None => return,
+        };
+
+        let result = match command {
+            Ok(command) => {
+                let result = self
+                    .inner
+                    .handle_command(command, &ctx.sender, &ctx.room_id)
+                    .await;
+
+                ctx.reply_result(result).await
+            }
+            Err(err) => ctx.reply_error(err).await,
+        };
+
+        if let Err(err) = result {
+            error!("Failed to send reply: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2020, 10, 18).and_hms(12, 0, 0)
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("2h").unwrap(), Duration::hours(2));
+        assert_eq!(
+            parse_duration("1h30m").unwrap(),
+            Duration::hours(1) + Duration::minutes(30)
+        );
+        assert!(parse_duration("soon").is_err());
+    }
+
+    #[test]
+    fn test_parse_in() {
+        let command = parse_command(".remind me in 2h to deploy", now()).unwrap();
+
+        assert_eq!(
+            command.unwrap(),
+            Command::Add {
+                target: Target::Me,
+                schedule: Schedule {
+                    due: now() + Duration::hours(2),
+                    repeat: None,
+                },
+                message: "deploy".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_at_tomorrow() {
+        let command = parse_command(".remind #room at 09:00 tomorrow standup", now()).unwrap();
+
+        assert_eq!(
+            command.unwrap(),
+            Command::Add {
+                target: Target::Room("#room".to_string()),
+                schedule: Schedule {
+                    due: Utc.ymd(2020, 10, 19).and_hms(9, 0, 0),
+                    repeat: None,
+                },
+                message: "standup".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_at_passed_time_rolls_over() {
+        let command = parse_command(".remind me at 11:00 lunch", now()).unwrap();
+
+        match command.unwrap() {
+            Command::Add { schedule, .. } => {
+                assert_eq!(schedule.due, Utc.ymd(2020, 10, 19).and_hms(11, 0, 0))
+            }
+            command => panic!("unexpected command: {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_parse_every() {
+        let command = parse_command(".remind me every 1d at 09:00 to water plants", now());
+
+        assert_eq!(
+            command.unwrap().unwrap(),
+            Command::Add {
+                target: Target::Me,
+                schedule: Schedule {
+                    due: Utc.ymd(2020, 10, 19).and_hms(9, 0, 0),
+                    repeat: Some(Duration::days(1)),
+                },
+                message: "water plants".to_string(),
+            }
+        );
+
+        assert!(parse_command(".remind me every 10s spam", now())
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_overflow() {
+        assert!(matches!(
+            parse_command(".remind me in 99999999999999w x", now()),
+            Some(Err(ReminderError::InvalidDuration(_)))
+        ));
+        assert!(matches!(
+            parse_command(".remind me in 9999999999w x", now()),
+            Some(Err(ReminderError::InvalidDuration(_)))
+        ));
+        assert!(matches!(
+            parse_command(".remind me every 9999999999w x", now()),
+            Some(Err(ReminderError::InvalidDuration(_)))
+        ));
+    }
+
+    #[test]
+    fn test_next_occurrence() {
+        let day = Duration::days(1).num_seconds();
+
+        assert_eq!(
+            next_occurrence(now() - Duration::hours(36), day, now()),
+            Some(now() + Duration::hours(12))
+        );
+        assert_eq!(next_occurrence(now(), i64::MAX / 1000, now()), None);
+    }
+
+    #[test]
+    fn test_parse_list_and_cancel() {
+        assert_eq!(
+            parse_command(".reminders", now()).unwrap().unwrap(),
+            Command::List
+        );
+        assert_eq!(
+            parse_command(".remind list", now()).unwrap().unwrap(),
+            Command::List
+        );
+        assert_eq!(
+            parse_command(".remind cancel #3", now()).unwrap().unwrap(),
+            Command::Cancel(3)
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse_command("remind me in 2h", now()).is_none());
+        assert!(parse_command(".remind me in 2h", now()).unwrap().is_err());
+        assert!(parse_command(".remind me at 25:00 nope", now())
+            .unwrap()
+            .is_err());
+    }
+}
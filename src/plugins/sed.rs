@@ -0,0 +1,369 @@
+//! Corrects recent messages with sed-style substitutions
+//!
+//! Usage:
+//!
+//! ```text
+//! s/<pattern>/<replacement>/[flags]
+//! ```
+//!
+//! The substitution is applied to the sender's most recent message that the pattern matches. In
+//! the replacement, `&` is the whole match and `\1` to `\9` are groups. Flags:
+//!
+//! - `g` replaces every match instead of only the first
+//! - `i` matches case-insensitively
+//! - `a` corrects anyone's message, not only the sender's
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::error;
+use matrix_sdk::{events::room::message::TextMessageEventContent, Client};
+use regex::{Regex, RegexBuilder};
+use tokio::{task, time};
+
+use crate::config::SedConfig;
+use crate::context::MessageContext;
+use crate::history::{History, HistoryMessage};
+use crate::plugin::{Plugin, PluginContext};
+use crate::Error;
+
+/// The maximum number of bytes in a pattern or a replacement
+const MAX_EXPRESSION_LEN: usize = 256;
+/// The maximum number of bytes in a corrected message
+const MAX_CORRECTION_LEN: usize = 4096;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SedError {
+    #[error(
+        "the pattern and the replacement can be at most {} bytes long",
+        MAX_EXPRESSION_LEN
+    )]
+    ExpressionTooLong,
+    #[error("{0}")]
+    InvalidPattern(#[from] regex::Error),
+    #[error("no recent message matches that")]
+    NoMatch,
+    #[error("the corrected message would be too long")]
+    CorrectionTooLong,
+    #[error("the substitution took too long")]
+    Timeout,
+    #[error("failed to fetch recent messages: {0}")]
+    HistoryError(#[from] matrix_sdk::Error),
+}
+
+#[derive(Debug, PartialEq)]
+struct Substitution {
+    pattern: String,
+    /// The replacement in the syntax of the regex crate
+    replacement: String,
+    /// Replace every match instead of only the first
+    global: bool,
+    ignore_case: bool,
+    /// Correct anyone's message, not only the sender's
+    anyone: bool,
+}
+
+pub struct SedPlugin {
+    client: Client,
+    history: Arc<History>,
+    config: SedConfig,
+}
+
+/// Splits `text` at every `/` that isn't escaped with a backslash, unescaping the escaped ones
+fn split_unescaped(text: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        let part = parts.last_mut().expect("there is always a part");
+
+        match c {
+            '\\' => match chars.next() {
+                Some('/') => part.push('/'),
+                Some(next) => {
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => part.push('\\'),
+            },
+            '/' => parts.push(String::new()),
+            c => part.push(c),
+        }
+    }
+
+    parts
+}
+
+/// Converts a sed replacement to the syntax of the regex crate, where groups are written as `$1`
+fn convert_replacement(replacement: &str) -> String {
+    let mut converted = String::new();
+    let mut chars = replacement.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => converted.push_str("${0}"),
+            '$' => converted.push_str("$$"),
+            '\\' => match chars.next() {
+                Some(digit) if digit.is_ascii_digit() => {
+                    converted.push_str(&format!("${{{}}}", digit))
+                }
+                Some('$') => converted.push_str("$$"),
+                Some(next) => converted.push(next),
+                None => converted.push('\\'),
+            },
+            c => converted.push(c),
+        }
+    }
+
+    converted
+}
+
+/// Parses the body of a text message as a substitution
+///
+/// Returns `None` if the message isn't a substitution at all
+fn parse_substitution(body: &str) -> Option<Result<Substitution, SedError>> {
+    let parts = split_unescaped(body.trim().strip_prefix("s/")?);
+    let (pattern, replacement, flags) = match parts.as_slice() {
+        [pattern, replacement] => (pattern, replacement, ""),
+        [pattern, replacement, flags] => (pattern, replacement, flags.as_str()),
+        _ => return None,
+    };
+
+    // Anything else after the last slash is most likely just a sentence that starts with "s/"
+    if !flags.chars().all(|flag| "gia".contains(flag)) {
+        return None;
+    }
+
+    if pattern.len() > MAX_EXPRESSION_LEN || replacement.len() > MAX_EXPRESSION_LEN {
+        return Some(Err(SedError::ExpressionTooLong));
+    }
+
+    Some(Ok(Substitution {
+        pattern: pattern.clone(),
+        replacement: convert_replacement(replacement),
+        global: flags.contains('g'),
+        ignore_case: flags.contains('i'),
+        anyone: flags.contains('a'),
+    }))
+}
+
+/// Replaces the first match of `regex` in `text`, or every match if `global` is set
+///
+/// Fails as soon as the result grows longer than [`MAX_CORRECTION_LEN`], so a pattern that
+/// matches everywhere can't make us build an enormous message.
+fn replace(regex: &Regex, text: &str, replacement: &str, global: bool) -> Result<String, SedError> {
+    let limit = if global { usize::MAX } else { 1 };
+    let mut corrected = String::new();
+    let mut last_end = 0;
+
+    for captures in regex.captures_iter(text).take(limit) {
+        let m = captures.get(0).expect("the whole match is always a group");
+
+        corrected.push_str(&text[last_end..m.start()]);
+        captures.expand(replacement, &mut corrected);
+        last_end = m.end();
+
+        if corrected.len() > MAX_CORRECTION_LEN {
+            return Err(SedError::CorrectionTooLong);
+        }
+    }
+
+    corrected.push_str(&text[last_end..]);
+
+    if corrected.len() > MAX_CORRECTION_LEN {
+        return Err(SedError::CorrectionTooLong);
+    }
+
+    Ok(corrected)
+}
+
+impl SedPlugin {
+    /// Finds the message that `substitution` is meant for and returns our reply with the
+    /// corrected message
+    async fn correct(
+        &self,
+        ctx: &MessageContext,
+        substitution: &Substitution,
+    ) -> Result<String, SedError> {
+        let regex = RegexBuilder::new(&substitution.pattern)
+            .case_insensitive(substitution.ignore_case)
+            .size_limit(self.config.max_regex_size)
+            .dfa_size_limit(self.config.max_regex_size)
+            .build()?;
+        let own_user_id = self.client.user_id().await;
+        let is_candidate = |message: &HistoryMessage| {
+            message.event_id != *ctx.original_event_id()
+                && Some(&message.sender) != own_user_id.as_ref()
+                && (substitution.anyone || message.sender == ctx.sender)
+                && parse_substitution(&message.body).is_none()
+        };
+
+        let recent = self
+            .history
+            .recent(&ctx.room_id, self.config.max_messages)
+            .into_iter()
+            .filter(|message| is_candidate(message))
+            .collect();
+        let found = match self.substitute(&regex, substitution, recent).await? {
+            Some(found) => found,
+            None => {
+                // The messages we've seen since we started may not go back far enough
+                let fetched = self
+                    .history
+                    .fetch(&ctx.room_id, self.config.max_messages)
+                    .await?
+                    .into_iter()
+                    .filter(|message| is_candidate(message))
+                    .collect();
+
+                self.substitute(&regex, substitution, fetched)
+                    .await?
+                    .ok_or(SedError::NoMatch)?
+            }
+        };
+        let (message, corrected) = found;
+
+        if message.sender == ctx.sender {
+            Ok(format!(
+                "{} meant: {}",
+                message.sender.localpart(),
+                corrected
+            ))
+        } else {
+            Ok(format!(
+                "{} thinks {} meant: {}",
+                ctx.sender.localpart(),
+                message.sender.localpart(),
+                corrected
+            ))
+        }
+    }
+
+    /// Applies the substitution to the first of `messages` that `regex` matches, if any
+    ///
+    /// Matching runs on a blocking thread, which we stop waiting for after the configured
+    /// timeout. The regex crate matches in linear time, so the thread still finishes soon.
+    async fn substitute(
+        &self,
+        regex: &Regex,
+        substitution: &Substitution,
+        messages: Vec<HistoryMessage>,
+    ) -> Result<Option<(HistoryMessage, String)>, SedError> {
+        let regex = regex.clone();
+        let replacement = substitution.replacement.clone();
+        let global = substitution.global;
+        let task = task::spawn_blocking(move || {
+            messages
+                .into_iter()
+                .find(|message| regex.is_match(&message.body))
+                .map(|message| {
+                    let corrected = replace(&regex, &message.body, &replacement, global)?;
+
+                    Ok((message, corrected))
+                })
+        });
+
+        match time::timeout(Duration::from_millis(self.config.timeout), task).await {
+            Ok(found) => found.expect("substituting doesn't panic").transpose(),
+            Err(_) => Err(SedError::Timeout),
+        }
+    }
+}
+
+#[async_trait]
+impl Plugin for SedPlugin {
+    fn new(ctx: PluginContext) -> Result<Self, Error> {
+        Ok(SedPlugin {
+            client: ctx.client,
+            history: ctx.history,
+            config: ctx.config.plugins.sed.clone(),
+        })
+    }
+
+    async fn on_room_text_message(&self, ctx: &MessageContext, message: &TextMessageEventContent) {
+        let result = match parse_substitution(&message.body) {
+            Some(Ok(substitution)) => {
+                ctx.reply_result(self.correct(ctx, &substitution).await)
+                    .await
+            }
+            Some(Err(err)) => ctx.reply_error(err).await,
+            None => return,
+        };
+
+        if let Err(err) = result {
+            error!("Failed to send reply: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn substitute(body: &str, text: &str) -> Result<String, SedError> {
+        let substitution = parse_substitution(body).unwrap()?;
+        let regex = RegexBuilder::new(&substitution.pattern)
+            .case_insensitive(substitution.ignore_case)
+            .build()?;
+
+        replace(&regex, text, &substitution.replacement, substitution.global)
+    }
+
+    #[test]
+    fn test_parse_substitution() {
+        assert_eq!(
+            parse_substitution("s/teh/the/gi").unwrap().unwrap(),
+            Substitution {
+                pattern: "teh".to_string(),
+                replacement: "the".to_string(),
+                global: true,
+                ignore_case: true,
+                anyone: false,
+            }
+        );
+        assert_eq!(
+            parse_substitution(r"s/a\/b/\1 & $/a").unwrap().unwrap(),
+            Substitution {
+                pattern: "a/b".to_string(),
+                replacement: "${1} ${0} $$".to_string(),
+                global: false,
+                ignore_case: false,
+                anyone: true,
+            }
+        );
+        assert!(parse_substitution("s/foo/bar").unwrap().is_ok());
+        assert!(parse_substitution("s/he said/she said/ but why").is_none());
+        assert!(parse_substitution("s/foo").is_none());
+        assert!(parse_substitution("sure/why not").is_none());
+        assert!(matches!(
+            parse_substitution(&format!("s/{}/x/", "a".repeat(300))),
+            Some(Err(SedError::ExpressionTooLong))
+        ));
+    }
+
+    #[test]
+    fn test_replace() {
+        assert_eq!(
+            substitute("s/teh/the/", "teh cat teh").unwrap(),
+            "the cat teh"
+        );
+        assert_eq!(
+            substitute("s/teh/the/g", "teh cat teh").unwrap(),
+            "the cat the"
+        );
+        assert_eq!(substitute("s/TEH/the/i", "teh cat").unwrap(), "the cat");
+        assert_eq!(
+            substitute(r"s/(\w+) (\w+)/\2 \1/", "hello world").unwrap(),
+            "world hello"
+        );
+        assert_eq!(substitute("s/cat/[&]/", "teh cat").unwrap(), "teh [cat]");
+        assert!(matches!(
+            substitute(
+                &format!("s/x?/{}/g", "y".repeat(200)),
+                &"many characters ".repeat(3)
+            ),
+            Err(SedError::CorrectionTooLong)
+        ));
+    }
+}
@@ -0,0 +1,263 @@
+//! Replies with the title of web pages that are linked in rooms
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use log::{debug, error};
+use matrix_sdk::events::room::message::TextMessageEventContent;
+use regex::Regex;
+use scraper::{Html, Selector};
+use url::Url;
+
+use crate::config::UrlPreviewConfig;
+use crate::context::MessageContext;
+use crate::http::{HttpClient, HttpError};
+use crate::plugin::{Plugin, PluginContext};
+use crate::Error;
+
+lazy_static! {
+    pub static ref URL: Regex = Regex::new(r#"https?://[^\s<>"]+"#).unwrap();
+    pub static ref TITLE_SELECTOR: Selector = Selector::parse("title").unwrap();
+    pub static ref OG_TITLE_SELECTOR: Selector =
+        Selector::parse(r#"meta[property="og:title"]"#).unwrap();
+    pub static ref OG_DESCRIPTION_SELECTOR: Selector =
+        Selector::parse(r#"meta[property="og:description"]"#).unwrap();
+}
+
+/// The maximum number of characters of a description to include in a reply
+const MAX_DESCRIPTION_LEN: usize = 200;
+
+pub struct UrlPreviewPlugin {
+    http: HttpClient,
+    config: UrlPreviewConfig,
+}
+
+/// What to say about a fetched URL
+#[derive(Debug, PartialEq)]
+enum Preview {
+    /// An HTML page with a title and an optional description
+    Page {
+        title: String,
+        description: Option<String>,
+    },
+    /// Something that isn't an HTML page
+    Other {
+        content_type: String,
+        size: Option<u64>,
+    },
+}
+
+/// Returns all the distinct http(s) URLs in `text`
+fn find_urls(text: &str) -> Vec<Url> {
+    let mut urls: Vec<Url> = vec![];
+
+    for m in URL.find_iter(text) {
+        // Don't include trailing punctuation, which is most likely part of the sentence
+        let candidate = m
+            .as_str()
+            .trim_end_matches(|c| matches!(c, '.' | ',' | ')' | '!' | '?' | ':' | ';' | '\''));
+
+        if let Ok(url) = Url::parse(candidate) {
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+    }
+
+    urls
+}
+
+/// Collapses all runs of whitespace in `text` to a single space
+fn normalize_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Extracts the title and description from an HTML page
+fn parse_page(body: &str) -> Option<Preview> {
+    let document = Html::parse_document(body);
+    let meta_content = |selector: &Selector| {
+        document
+            .select(selector)
+            .next()
+            .and_then(|element| element.value().attr("content"))
+            .map(normalize_whitespace)
+            .filter(|content| !content.is_empty())
+    };
+
+    let title = meta_content(&OG_TITLE_SELECTOR).or_else(|| {
+        document
+            .select(&TITLE_SELECTOR)
+            .next()
+            .map(|element| normalize_whitespace(&element.text().collect::<String>()))
+            .filter(|title| !title.is_empty())
+    })?;
+    let description = meta_content(&OG_DESCRIPTION_SELECTOR).map(|description| {
+        if description.chars().count() > MAX_DESCRIPTION_LEN {
+            let truncated: String = description.chars().take(MAX_DESCRIPTION_LEN).collect();
+
+            format!("{}…", truncated.trim_end())
+        } else {
+            description
+        }
+    });
+
+    Some(Preview::Page { title, description })
+}
+
+/// Formats a number of bytes in a human-readable way
+fn format_size(size: u64) -> String {
+    match size {
+        s if s >= 1024 * 1024 => format!("{:.1} MiB", s as f64 / (1024.0 * 1024.0)),
+        s if s >= 1024 => format!("{:.1} KiB", s as f64 / 1024.0),
+        s => format!("{} B", s),
+    }
+}
+
+impl Preview {
+    fn to_message(&self) -> String {
+        match self {
+            Preview::Page {
+                title,
+                description: Some(description),
+            } => format!("[ {} ] - {}", title, description),
+            Preview::Page { title, .. } => format!("[ {} ]", title),
+            Preview::Other {
+                content_type,
+                size: Some(size),
+            } => format!("[ {}, {} ]", content_type, format_size(*size)),
+            Preview::Other { content_type, .. } => format!("[ {} ]", content_type),
+        }
+    }
+}
+
+impl UrlPreviewPlugin {
+    /// Returns whether the host of `url` is in the list of ignored hosts
+    fn is_ignored_host(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return true,
+        };
+
+        self.config.ignored_hosts.iter().any(|ignored| {
+            let ignored = ignored.to_lowercase();
+
+            host == ignored || host.ends_with(&format!(".{}", ignored))
+        })
+    }
+
+    /// Fetches `url` and returns a preview of it, if there's anything worth saying
+    async fn preview(&self, url: &Url) -> Result<Option<Preview>, HttpError> {
+        let response = self
+            .http
+            .get(url.clone())
+            .max_body_size(self.config.max_body_size)
+            .timeout(Duration::from_secs(self.config.timeout))
+            .send()
+            .await?
+            .error_for_status()?;
+        let content_type = response
+            .content_type()
+            .unwrap_or_else(|| "application/octet-stream".to_string());
+
+        if content_type != "text/html" && content_type != "application/xhtml+xml" {
+            return Ok(Some(Preview::Other {
+                content_type,
+                size: response.content_length(),
+            }));
+        }
+
+        Ok(parse_page(&response.text()))
+    }
+}
+
+#[async_trait]
+impl Plugin for UrlPreviewPlugin {
+    fn new(ctx: PluginContext) -> Result<Self, Error> {
+        Ok(UrlPreviewPlugin {
+            http: ctx.http,
+            config: ctx.config.plugins.url_preview.clone(),
+        })
+    }
+
+    fn shows_typing(&self) -> bool {
+        // Previews aren't something anyone is waiting for
+        false
+    }
+
+    async fn on_room_text_message(&self, ctx: &MessageContext, message: &TextMessageEventContent) {
+        // Leave search results and the like to the plugins that posted them
+        if message.body.starts_with('.') {
+            return;
+        }
+
+        let urls = find_urls(&message.body);
+
+        for url in urls.iter().take(self.config.max_urls) {
+            if self.is_ignored_host(url) {
+                continue;
+            }
+
+            match self.preview(url).await {
+                Ok(Some(preview)) => {
+                    if let Err(err) = ctx.reply_text(preview.to_message()).await {
+                        error!("Failed to send url preview: {}", err);
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => debug!("Could not preview {}: {}", url, err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_urls() {
+        let urls = find_urls(
+            "see https://example.com/a?b=c, and (http://example.org/). https://example.com/a?b=c",
+        );
+
+        assert_eq!(
+            urls,
+            vec![
+                Url::parse("https://example.com/a?b=c").unwrap(),
+                Url::parse("http://example.org/").unwrap()
+            ]
+        );
+        assert!(find_urls("no links here, ftp://example.com").is_empty());
+    }
+
+    #[test]
+    fn test_parse_page() {
+        let preview = parse_page("<html><head><title>\n  Example\n  Domain </title></head></html>");
+
+        assert_eq!(
+            preview,
+            Some(Preview::Page {
+                title: "Example Domain".to_string(),
+                description: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_page_opengraph() {
+        let preview = parse_page(
+            r#"<html><head>
+                <title>Fallback</title>
+                <meta property="og:title" content="Open Graph Title">
+                <meta property="og:description" content="A description">
+            </head></html>"#,
+        );
+
+        assert_eq!(
+            preview.unwrap().to_message(),
+            "[ Open Graph Title ] - A description"
+        );
+        assert_eq!(parse_page("<html><body>untitled</body></html>"), None);
+    }
+}
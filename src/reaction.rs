@@ -0,0 +1,274 @@
+//! Reactions to messages, and messages that use reactions as buttons
+//!
+//! The Matrix SDK doesn't know about `m.reaction` events yet, so they're sent and received as
+//! custom events. Removing a reaction redacts it, so the presses of buttons are remembered until
+//! then, to tell the plugin that the button was released.
+
+use chrono::{DateTime, Duration, Utc};
+use matrix_sdk::events::{custom::CustomEventContent, AnyMessageEventContent};
+use matrix_sdk_common::identifiers::{EventId, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{Storage, StorageError};
+
+/// The event type of reactions
+pub const REACTION_EVENT_TYPE: &str = "m.reaction";
+
+/// The number of days to keep listening for button presses on a message
+const MAX_BUTTONS_AGE_DAYS: i64 = 30;
+
+/// A reaction to a message
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reaction {
+    /// The message that was reacted to
+    pub event_id: EventId,
+    /// The reaction itself, usually an emoji
+    pub key: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReactionEventContent {
+    #[serde(rename = "m.relates_to")]
+    relates_to: Relation,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "rel_type")]
+enum Relation {
+    #[serde(rename = "m.annotation")]
+    Annotation { event_id: EventId, key: String },
+}
+
+impl Reaction {
+    pub fn new<S: Into<String>>(event_id: EventId, key: S) -> Reaction {
+        Reaction {
+            event_id,
+            key: key.into(),
+        }
+    }
+
+    /// Parses the content of a custom event, returning `None` if it isn't a reaction
+    pub fn from_custom_content(content: &CustomEventContent) -> Option<Reaction> {
+        if content.event_type != REACTION_EVENT_TYPE {
+            return None;
+        }
+
+        let content: ReactionEventContent = serde_json::from_value(content.json.clone()).ok()?;
+        let Relation::Annotation { event_id, key } = content.relates_to;
+
+        Some(Reaction { event_id, key })
+    }
+
+    /// Returns the event content to send for this reaction
+    pub fn to_content(&self) -> AnyMessageEventContent {
+        let content = ReactionEventContent {
+            relates_to: Relation::Annotation {
+                event_id: self.event_id.clone(),
+                key: self.key.clone(),
+            },
+        };
+
+        AnyMessageEventContent::Custom(CustomEventContent {
+            event_type: REACTION_EVENT_TYPE.to_string(),
+            json: serde_json::to_value(content).expect("reactions can always be serialized"),
+        })
+    }
+}
+
+/// The buttons of a message
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Buttons {
+    /// The name of the plugin that sent the message
+    pub plugin: String,
+    /// The reactions that count as button presses
+    pub keys: Vec<String>,
+    /// When the message was sent
+    pub sent_at: DateTime<Utc>,
+}
+
+/// Keeps track of which messages have buttons, and which plugin they belong to
+///
+/// The buttons are persisted, so they keep working after a restart.
+pub struct ButtonMap {
+    storage: Storage,
+}
+
+impl ButtonMap {
+    pub fn new(storage: Storage) -> ButtonMap {
+        ButtonMap { storage }
+    }
+
+    /// Returns the buttons of the message `event_id`, if any
+    pub async fn get(&self, event_id: &EventId) -> Result<Option<Buttons>, StorageError> {
+        self.storage.get(event_id.as_str()).await
+    }
+
+    /// Remembers that the message `event_id` sent by `plugin` has the given buttons
+    pub async fn insert(
+        &self,
+        event_id: &EventId,
+        plugin: &str,
+        keys: &[&str],
+    ) -> Result<(), StorageError> {
+        let buttons = Buttons {
+            plugin: plugin.to_string(),
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+            sent_at: Utc::now(),
+        };
+
+        self.storage.set(event_id.as_str(), &buttons).await
+    }
+
+    /// Stops listening for button presses on messages that are too old
+    pub async fn prune(&self) -> Result<usize, StorageError> {
+        let oldest = Utc::now() - Duration::days(MAX_BUTTONS_AGE_DAYS);
+        let mut pruned = 0;
+
+        for (key, buttons) in self.storage.list::<Buttons>("").await? {
+            if buttons.sent_at < oldest && self.storage.delete(&key).await? {
+                pruned += 1;
+            }
+        }
+
+        Ok(pruned)
+    }
+}
+
+/// A press of a button, which is a reaction
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Press {
+    /// The room the reaction was sent in
+    pub room_id: RoomId,
+    /// The user that pressed the button
+    pub sender: UserId,
+    /// The message whose button was pressed
+    pub event_id: EventId,
+    /// The button that was pressed
+    pub key: String,
+    /// The name of the plugin that the button belongs to
+    pub plugin: String,
+    /// When the button was pressed
+    pub pressed_at: DateTime<Utc>,
+}
+
+impl Press {
+    /// Returns the reaction that pressed the button
+    pub fn reaction(&self) -> Reaction {
+        Reaction::new(self.event_id.clone(), self.key.clone())
+    }
+}
+
+/// Keeps track of which reactions pressed buttons, so plugins can be told when they're removed
+///
+/// The presses are persisted for as long as the buttons are listened to.
+pub struct PressMap {
+    storage: Storage,
+}
+
+impl PressMap {
+    pub fn new(storage: Storage) -> PressMap {
+        PressMap { storage }
+    }
+
+    /// Remembers that the reaction `event_id` is `press`
+    pub async fn insert(&self, event_id: &EventId, press: &Press) -> Result<(), StorageError> {
+        self.storage.set(event_id.as_str(), press).await
+    }
+
+    /// Forgets the reaction `event_id`, returning the button press it was, if any
+    pub async fn remove(&self, event_id: &EventId) -> Result<Option<Press>, StorageError> {
+        let press = self.storage.get(event_id.as_str()).await?;
+
+        if press.is_some() {
+            self.storage.delete(event_id.as_str()).await?;
+        }
+
+        Ok(press)
+    }
+
+    /// Forgets presses of buttons that are no longer listened to
+    pub async fn prune(&self) -> Result<usize, StorageError> {
+        let oldest = Utc::now() - Duration::days(MAX_BUTTONS_AGE_DAYS);
+        let mut pruned = 0;
+
+        for (key, press) in self.storage.list::<Press>("").await? {
+            if press.pressed_at < oldest && self.storage.delete(&key).await? {
+                pruned += 1;
+            }
+        }
+
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    fn event_id(id: &str) -> EventId {
+        EventId::try_from(id).unwrap()
+    }
+
+    #[test]
+    fn test_parse_reaction() {
+        let reaction = Reaction::new(event_id("$message:example.com"), "👍");
+
+        match reaction.to_content() {
+            AnyMessageEventContent::Custom(content) => {
+                assert_eq!(content.json["m.relates_to"]["rel_type"], "m.annotation");
+                assert_eq!(Reaction::from_custom_content(&content), Some(reaction));
+            }
+            content => panic!("unexpected content: {:?}", content),
+        }
+
+        let other = CustomEventContent {
+            event_type: REACTION_EVENT_TYPE.to_string(),
+            json: serde_json::json!({
+                "m.relates_to": { "rel_type": "m.reference", "event_id": "$message:example.com" }
+            }),
+        };
+        assert_eq!(Reaction::from_custom_content(&other), None);
+    }
+
+    #[tokio::test]
+    async fn test_button_map() {
+        let buttons = ButtonMap::new(Storage::new(Arc::new(MemoryBackend::new()), "buttons"));
+        let message = event_id("$message:example.com");
+
+        buttons
+            .insert(&message, "PollPlugin", &["👍", "👎"])
+            .await
+            .unwrap();
+
+        let stored = buttons.get(&message).await.unwrap().unwrap();
+        assert_eq!(stored.plugin, "PollPlugin");
+        assert_eq!(stored.keys, vec!["👍", "👎"]);
+        assert_eq!(buttons.prune().await.unwrap(), 0);
+        assert_eq!(
+            buttons.get(&event_id("$other:example.com")).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_press_map() {
+        let presses = PressMap::new(Storage::new(Arc::new(MemoryBackend::new()), "presses"));
+        let reaction = event_id("$reaction:example.com");
+        let press = Press {
+            room_id: RoomId::try_from("!room:example.com").unwrap(),
+            sender: UserId::try_from("@alice:example.com").unwrap(),
+            event_id: event_id("$message:example.com"),
+            key: "👍".to_string(),
+            plugin: "PollPlugin".to_string(),
+            pressed_at: Utc::now(),
+        };
+
+        presses.insert(&reaction, &press).await.unwrap();
+        assert_eq!(presses.prune().await.unwrap(), 0);
+        assert_eq!(presses.remove(&reaction).await.unwrap(), Some(press));
+        assert_eq!(presses.remove(&reaction).await.unwrap(), None);
+    }
+}
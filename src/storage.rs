@@ -0,0 +1,197 @@
+//! Persistent key-value storage for plugins
+//!
+//! Every plugin is handed a [`Storage`] handle that is scoped to its own namespace, so plugins
+//! can't read or clobber each others' data. Values are serialized as JSON.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(test)]
+mod memory;
+mod sqlite;
+
+#[cfg(test)]
+pub use memory::MemoryBackend;
+pub use sqlite::SqliteBackend;
+
+#[derive(thiserror::Error, Debug)]
+pub enum StorageError {
+    #[error("database error")]
+    DatabaseError(#[from] sqlx::Error),
+    #[error("unable to (de)serialize value")]
+    SerializationError(#[from] serde_json::Error),
+}
+
+/// A function that computes the new value of a key from its current value
+///
+/// Returning `None` deletes the key.
+pub type UpdateFn =
+    Box<dyn FnOnce(Option<Vec<u8>>) -> Result<Option<Vec<u8>>, StorageError> + Send>;
+
+/// A storage backend that keeps raw values by namespace and key
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Returns the value of `key`, if set
+    async fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Sets `key` to `value`, replacing any previous value
+    async fn set(&self, namespace: &str, key: &str, value: Vec<u8>) -> Result<(), StorageError>;
+
+    /// Deletes `key`, returning whether it existed
+    async fn delete(&self, namespace: &str, key: &str) -> Result<bool, StorageError>;
+
+    /// Returns all the keys and values where the key starts with `prefix`, ordered by key
+    async fn list(
+        &self,
+        namespace: &str,
+        prefix: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, StorageError>;
+
+    /// Atomically replaces the value of `key` with the result of `f`, returning the new value
+    async fn update(
+        &self,
+        namespace: &str,
+        key: &str,
+        f: UpdateFn,
+    ) -> Result<Option<Vec<u8>>, StorageError>;
+}
+
+/// A handle to the storage of a single namespace
+#[derive(Clone)]
+pub struct Storage {
+    backend: Arc<dyn StorageBackend>,
+    namespace: String,
+}
+
+impl Storage {
+    /// Returns a new handle to `namespace` in the given `backend`
+    pub fn new<S: Into<String>>(backend: Arc<dyn StorageBackend>, namespace: S) -> Storage {
+        Storage {
+            backend,
+            namespace: namespace.into(),
+        }
+    }
+
+    /// Returns the value of `key`, if set
+    pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, StorageError> {
+        match self.backend.get(&self.namespace, key).await? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets `key` to `value`
+    pub async fn set<T: Serialize + ?Sized>(
+        &self,
+        key: &str,
+        value: &T,
+    ) -> Result<(), StorageError> {
+        let value = serde_json::to_vec(value)?;
+
+        self.backend.set(&self.namespace, key, value).await
+    }
+
+    /// Deletes `key`, returning whether it existed
+    pub async fn delete(&self, key: &str) -> Result<bool, StorageError> {
+        self.backend.delete(&self.namespace, key).await
+    }
+
+    /// Returns all the keys and values where the key starts with `prefix`, ordered by key
+    pub async fn list<T: DeserializeOwned>(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, T)>, StorageError> {
+        self.backend
+            .list(&self.namespace, prefix)
+            .await?
+            .into_iter()
+            .map(|(key, value)| Ok((key, serde_json::from_slice(&value)?)))
+            .collect()
+    }
+
+    /// Atomically replaces the value of `key` with the result of `f`, returning the new value
+    ///
+    /// If `f` returns `None`, the key is deleted.
+    pub async fn update<T, F>(&self, key: &str, f: F) -> Result<Option<T>, StorageError>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce(Option<T>) -> Option<T> + Send + 'static,
+    {
+        let update: UpdateFn = Box::new(move |value| {
+            let value = match value {
+                Some(value) => Some(serde_json::from_slice(&value)?),
+                None => None,
+            };
+
+            match f(value) {
+                Some(value) => Ok(Some(serde_json::to_vec(&value)?)),
+                None => Ok(None),
+            }
+        });
+
+        match self.backend.update(&self.namespace, key, update).await? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn exercise_backend(backend: Arc<dyn StorageBackend>) {
+        let storage = Storage::new(backend.clone(), "test");
+        let other = Storage::new(backend, "other");
+
+        assert_eq!(storage.get::<u64>("counter").await.unwrap(), None);
+
+        storage.set("counter", &1u64).await.unwrap();
+        assert_eq!(storage.get::<u64>("counter").await.unwrap(), Some(1));
+        assert_eq!(other.get::<u64>("counter").await.unwrap(), None);
+
+        let value = storage
+            .update("counter", |value: Option<u64>| value.map(|v| v + 1))
+            .await
+            .unwrap();
+        assert_eq!(value, Some(2));
+
+        storage.set("quote/1", "first").await.unwrap();
+        storage.set("quote/2", "second").await.unwrap();
+        other.set("quote/3", "third").await.unwrap();
+
+        let quotes: Vec<(String, String)> = storage.list("quote/").await.unwrap();
+        assert_eq!(
+            quotes,
+            vec![
+                ("quote/1".to_string(), "first".to_string()),
+                ("quote/2".to_string(), "second".to_string())
+            ]
+        );
+
+        assert!(storage.delete("quote/1").await.unwrap());
+        assert!(!storage.delete("quote/1").await.unwrap());
+        assert_eq!(storage.list::<String>("quote/").await.unwrap().len(), 1);
+
+        let value = storage
+            .update("counter", |_: Option<u64>| None)
+            .await
+            .unwrap();
+        assert_eq!(value, None);
+        assert_eq!(storage.get::<u64>("counter").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend() {
+        exercise_backend(Arc::new(MemoryBackend::new())).await;
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_backend() {
+        let backend = SqliteBackend::open_in_memory().await.unwrap();
+
+        exercise_backend(Arc::new(backend)).await;
+    }
+}
@@ -0,0 +1,94 @@
+//! An in-memory storage backend, mostly useful for tests
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use super::{StorageBackend, StorageError, UpdateFn};
+
+/// A storage backend that keeps everything in memory
+#[derive(Default)]
+pub struct MemoryBackend {
+    namespaces: Mutex<BTreeMap<String, BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemoryBackend {
+    /// Constructs and returns a new, empty backend
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    async fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let namespaces = self.namespaces.lock().unwrap();
+
+        Ok(namespaces
+            .get(namespace)
+            .and_then(|entries| entries.get(key))
+            .cloned())
+    }
+
+    async fn set(&self, namespace: &str, key: &str, value: Vec<u8>) -> Result<(), StorageError> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+
+        namespaces
+            .entry(namespace.to_string())
+            .or_default()
+            .insert(key.to_string(), value);
+
+        Ok(())
+    }
+
+    async fn delete(&self, namespace: &str, key: &str) -> Result<bool, StorageError> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+
+        Ok(namespaces
+            .get_mut(namespace)
+            .and_then(|entries| entries.remove(key))
+            .is_some())
+    }
+
+    async fn list(
+        &self,
+        namespace: &str,
+        prefix: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, StorageError> {
+        let namespaces = self.namespaces.lock().unwrap();
+        let entries = match namespaces.get(namespace) {
+            Some(entries) => entries,
+            None => return Ok(vec![]),
+        };
+
+        Ok(entries
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    async fn update(
+        &self,
+        namespace: &str,
+        key: &str,
+        f: UpdateFn,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        let entries = namespaces.entry(namespace.to_string()).or_default();
+
+        match f(entries.get(key).cloned())? {
+            Some(value) => {
+                entries.insert(key.to_string(), value.clone());
+
+                Ok(Some(value))
+            }
+            None => {
+                entries.remove(key);
+
+                Ok(None)
+            }
+        }
+    }
+}
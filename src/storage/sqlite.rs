@@ -0,0 +1,147 @@
+//! An SQLite storage backend
+
+use std::path::Path;
+
+use async_trait::async_trait;
+use log::debug;
+use sqlx::{sqlite::SqlitePool, Row};
+
+use super::{StorageBackend, StorageError, UpdateFn};
+
+/// A storage backend that keeps everything in a single SQLite table
+pub struct SqliteBackend {
+    pool: SqlitePool,
+}
+
+impl SqliteBackend {
+    /// Opens the database at `path`, creating it if it doesn't exist
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<SqliteBackend, StorageError> {
+        let url = format!("sqlite://{}", path.as_ref().display());
+
+        debug!("Opening storage database {}", url);
+
+        SqliteBackend::connect(&url).await
+    }
+
+    /// Opens a new database that only lives in memory
+    #[cfg(test)]
+    pub async fn open_in_memory() -> Result<SqliteBackend, StorageError> {
+        SqliteBackend::connect("sqlite::memory:").await
+    }
+
+    async fn connect(url: &str) -> Result<SqliteBackend, StorageError> {
+        // A single connection serializes all access, which is what makes `update` atomic and
+        // keeps in-memory databases from being split across connections
+        let pool = SqlitePool::builder().max_size(1).build(url).await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS kv (
+                namespace TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value BLOB NOT NULL,
+                PRIMARY KEY (namespace, key)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        Ok(SqliteBackend { pool })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SqliteBackend {
+    async fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let row = sqlx::query("SELECT value FROM kv WHERE namespace = ? AND key = ?")
+            .bind(namespace)
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|row| row.get("value")))
+    }
+
+    async fn set(&self, namespace: &str, key: &str, value: Vec<u8>) -> Result<(), StorageError> {
+        sqlx::query("INSERT OR REPLACE INTO kv (namespace, key, value) VALUES (?, ?, ?)")
+            .bind(namespace)
+            .bind(key)
+            .bind(value)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, namespace: &str, key: &str) -> Result<bool, StorageError> {
+        let affected = sqlx::query("DELETE FROM kv WHERE namespace = ? AND key = ?")
+            .bind(namespace)
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(affected > 0)
+    }
+
+    async fn list(
+        &self,
+        namespace: &str,
+        prefix: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, StorageError> {
+        let rows = sqlx::query(
+            "SELECT key, value FROM kv
+             WHERE namespace = ? AND substr(key, 1, length(?)) = ?
+             ORDER BY key",
+        )
+        .bind(namespace)
+        .bind(prefix)
+        .bind(prefix)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get("key"), row.get("value")))
+            .collect())
+    }
+
+    async fn update(
+        &self,
+        namespace: &str,
+        key: &str,
+        f: UpdateFn,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        let mut tx = self.pool.begin().await?;
+
+        let current: Option<Vec<u8>> =
+            sqlx::query("SELECT value FROM kv WHERE namespace = ? AND key = ?")
+                .bind(namespace)
+                .bind(key)
+                .fetch_optional(&mut tx)
+                .await?
+                .map(|row| row.get("value"));
+
+        let value = f(current)?;
+
+        match value {
+            Some(ref value) => {
+                sqlx::query("INSERT OR REPLACE INTO kv (namespace, key, value) VALUES (?, ?, ?)")
+                    .bind(namespace)
+                    .bind(key)
+                    .bind(value.clone())
+                    .execute(&mut tx)
+                    .await?;
+            }
+            None => {
+                sqlx::query("DELETE FROM kv WHERE namespace = ? AND key = ?")
+                    .bind(namespace)
+                    .bind(key)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(value)
+    }
+}
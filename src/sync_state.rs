@@ -0,0 +1,101 @@
+//! Remembers how far we've synced and which events have been dispatched to plugins, so restarts
+//! neither drop nor replay messages
+
+use chrono::{DateTime, Duration, Utc};
+use matrix_sdk_common::identifiers::EventId;
+
+use crate::storage::{Storage, StorageError};
+
+/// The number of days to remember dispatched events for
+const MAX_HANDLED_AGE_DAYS: i64 = 7;
+
+/// The key of the sync token
+const TOKEN_KEY: &str = "token";
+/// The prefix of the keys of dispatched events
+const HANDLED_PREFIX: &str = "handled:";
+
+fn handled_key(event_id: &EventId) -> String {
+    format!("{}{}", HANDLED_PREFIX, event_id)
+}
+
+pub struct SyncState {
+    storage: Storage,
+}
+
+impl SyncState {
+    pub fn new(storage: Storage) -> SyncState {
+        SyncState { storage }
+    }
+
+    /// Returns the token of the last sync that was handled, if any
+    pub async fn token(&self) -> Result<Option<String>, StorageError> {
+        self.storage.get(TOKEN_KEY).await
+    }
+
+    /// Remembers `token` as the token of the last sync that was handled
+    pub async fn set_token(&self, token: &str) -> Result<(), StorageError> {
+        self.storage.set(TOKEN_KEY, token).await
+    }
+
+    /// Returns whether `event_id` was dispatched before
+    pub async fn is_handled(&self, event_id: &EventId) -> Result<bool, StorageError> {
+        let handled_at: Option<DateTime<Utc>> = self.storage.get(&handled_key(event_id)).await?;
+
+        Ok(handled_at.is_some())
+    }
+
+    /// Remembers that `event_id` was dispatched
+    pub async fn mark_handled(&self, event_id: &EventId) -> Result<(), StorageError> {
+        let key = handled_key(event_id);
+
+        self.storage
+            .update(&key, |handled_at: Option<DateTime<Utc>>| {
+                Some(handled_at.unwrap_or_else(Utc::now))
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Forgets dispatched events that are too old to be sent to us again
+    pub async fn prune(&self) -> Result<usize, StorageError> {
+        let oldest = Utc::now() - Duration::days(MAX_HANDLED_AGE_DAYS);
+        let mut pruned = 0;
+
+        for (key, handled_at) in self.storage.list::<DateTime<Utc>>(HANDLED_PREFIX).await? {
+            if handled_at < oldest && self.storage.delete(&key).await? {
+                pruned += 1;
+            }
+        }
+
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_sync_state() {
+        let storage = Storage::new(Arc::new(MemoryBackend::new()), "sync");
+        let state = SyncState::new(storage);
+        let event_id = EventId::try_from("$event:example.com").unwrap();
+
+        assert_eq!(state.token().await.unwrap(), None);
+        state.set_token("s72594_4483_1934").await.unwrap();
+        assert_eq!(
+            state.token().await.unwrap().as_deref(),
+            Some("s72594_4483_1934")
+        );
+
+        assert!(!state.is_handled(&event_id).await.unwrap());
+        state.mark_handled(&event_id).await.unwrap();
+        state.mark_handled(&event_id).await.unwrap();
+        assert!(state.is_handled(&event_id).await.unwrap());
+        assert_eq!(state.prune().await.unwrap(), 0);
+    }
+}